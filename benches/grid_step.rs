@@ -0,0 +1,39 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use game::grid::{Atom, Grid, GRID_SIZE};
+use rand::prelude::*;
+
+/// A grid with the fraction of its cells solid and the rest gas at random pressures, the same
+/// for every run.
+fn seeded_grid(solid_fraction: f32) -> Grid {
+    let mut grid = Grid::new();
+    grid.randomize(solid_fraction);
+
+    let mut rng = StdRng::seed_from_u64(0);
+    for x in 0..GRID_SIZE {
+        for y in 0..GRID_SIZE {
+            if let Atom::Gas(_) = grid.inspect((x, y)) {
+                grid.set((x, y), Atom::Gas(rng.gen_range(-100.0..100.0)));
+            }
+        }
+    }
+    grid
+}
+
+// GRID_SIZE is a constant, so the grid's density varies instead of its size.
+fn grid_step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("grid_step");
+    for solid_fraction in [0.0, 0.3, 0.6] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(solid_fraction),
+            &solid_fraction,
+            |b, &solid_fraction| {
+                let mut grid = seeded_grid(solid_fraction);
+                b.iter(|| grid.step());
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, grid_step);
+criterion_main!(benches);
@@ -0,0 +1,500 @@
+use crate::grid::{EditorState, CAN_SAVE};
+use crate::math::{catmull_rom, Aabb};
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+const CAMERA_SAVE_PATH: &str = "nopush/camera_save.json";
+const DEFAULT_ROTATION: Vec2 = Vec2::ZERO;
+const DEFAULT_ZOOM: f32 = 1.0;
+const ORBIT_SPEED: f32 = 1.5; // Radians per second
+const ZOOM_SPEED: f32 = 1.0; // Logarithmic, so zooming in and out feel symmetrical.
+const MOUSE_ORBIT_SPEED: f32 = 0.005; // Radians per pixel
+const MIN_ZOOM: f32 = 0.25;
+const MAX_ZOOM: f32 = 16.0;
+const PERSPECTIVE_DISTANCE: f32 = 12.0; // In the scene's own units
+const FRAME_MARGIN: f32 = 0.9; // The fraction of the viewport that framed bounds fill
+const GIZMO_SIZE: f32 = 0.1; // The length of each axis in normalized coordinates
+const GIZMO_MARGIN: f32 = 0.05;
+const GIZMO_LINE_WIDTH: f32 = 3.0; // In pixels
+const SECONDS_PER_KEYFRAME: f32 = 2.0; // How long a tour takes between keyframes
+
+/// The perspective projection's settings, which the editor exposes.
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lens {
+    pub fov_degrees: f32, // Vertical
+    pub near: f32,        // In the scene's own units, like far
+    pub far: f32,
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Self {
+            fov_degrees: 45.0,
+            near: 0.1,
+            far: 100.0,
+        }
+    }
+}
+
+impl Lens {
+    /// The field of view in radians, kept away from 0 and 180 degrees where the projection
+    /// degenerates.
+    fn fov(&self) -> f32 {
+        self.fov_degrees.clamp(1.0, 179.0).to_radians()
+    }
+
+    /// The near and far planes, kept positive and apart.
+    fn planes(&self) -> (f32, f32) {
+        let near = self.near.max(0.001);
+        (near, self.far.max(near * 2.0))
+    }
+
+    fn projection(&self) -> Mat4 {
+        let (near, far) = self.planes();
+        // The aspect ratio is 1 because Gpu::render_mesh corrects for it.
+        Mat4::perspective_lh(self.fov(), 1.0, near, far)
+    }
+}
+
+/// Where the camera is looking. The camera eases its current pose toward a goal pose.
+#[derive(Clone, Copy)]
+struct Pose {
+    rotation: Vec2,
+    zoom: f32,
+    pan: Vec2,
+    focus: Vec3, // The point in the scene that the camera orbits
+}
+
+impl Pose {
+    const DEFAULT: Self = Self {
+        rotation: DEFAULT_ROTATION,
+        zoom: DEFAULT_ZOOM,
+        pan: Vec2::ZERO,
+        focus: Vec3::ZERO,
+    };
+
+    /// Moves the fraction t of the way toward the goal. Zoom is eased logarithmically so that
+    /// zooming in and out take the same time.
+    fn eased_toward(&self, goal: &Pose, t: f32) -> Pose {
+        Pose {
+            rotation: self.rotation.lerp(goal.rotation, t),
+            zoom: (self.zoom.ln() + (goal.zoom.ln() - self.zoom.ln()) * t).exp(),
+            pan: self.pan.lerp(goal.pan, t),
+            focus: self.focus.lerp(goal.focus, t),
+        }
+    }
+
+    fn state(&self) -> CameraState {
+        CameraState {
+            rotation: self.rotation,
+            zoom: self.zoom,
+            pan: self.pan,
+            focus: self.focus,
+        }
+    }
+
+    fn rotation_matrix(&self) -> Mat4 {
+        Mat4::from_rotation_x(self.rotation.y) * Mat4::from_rotation_y(self.rotation.x)
+    }
+
+    /// The lens is None for an orthographic projection.
+    fn transform(&self, scene: &Mat4, lens: Option<&Lens>) -> Mat4 {
+        let pan = Mat4::from_translation(self.pan.extend(0.0));
+        let rotation = self.rotation_matrix() * Mat4::from_translation(-self.focus);
+
+        if let Some(lens) = lens {
+            // The scene transform squashes depth to fit the orthographic clip volume, so the
+            // projection replaces it and views the scene from a distance in its own units.
+            let projection = lens.projection();
+            let view =
+                Mat4::from_translation(Vec3::new(0.0, 0.0, PERSPECTIVE_DISTANCE / self.zoom));
+            pan * projection * view * rotation
+        } else {
+            // Zoom only scales x and y so that the depth range stays within the clip volume.
+            let zoom = Mat4::from_scale(Vec3::new(self.zoom, self.zoom, 1.0));
+            pan * *scene * zoom * rotation
+        }
+    }
+}
+
+/// The camera's pose, saved next to the grid so that returning to a scene keeps the view.
+/// Missing fields, as in saves from before a field existed, take their defaults.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraState {
+    pub rotation: Vec2,
+    pub zoom: f32,
+    pub pan: Vec2,
+    pub focus: Vec3,
+}
+
+impl Default for CameraState {
+    fn default() -> Self {
+        Pose::DEFAULT.state()
+    }
+}
+
+impl CameraState {
+    /// Returns the default state if there's no valid save.
+    pub fn load() -> Self {
+        fs::read_to_string(CAMERA_SAVE_PATH)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        if !CAN_SAVE {
+            return;
+        }
+        let json = serde_json::to_string(self).expect("Failed to serialize camera state");
+        fs::write(CAMERA_SAVE_PATH, json).expect("Failed to write camera state");
+    }
+}
+
+/// Keyframed camera states that a tour plays back along Catmull-Rom splines, one keyframe every
+/// SECONDS_PER_KEYFRAME.
+#[derive(Default)]
+pub struct CameraPath {
+    keyframes: Vec<CameraState>,
+}
+
+impl CameraPath {
+    pub fn push(&mut self, keyframe: CameraState) {
+        self.keyframes.push(keyframe);
+    }
+
+    pub fn clear(&mut self) {
+        self.keyframes.clear();
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keyframes.len().saturating_sub(1) as f32 * SECONDS_PER_KEYFRAME
+    }
+
+    /// The state at the time in seconds, clamped to the path. Zoom is interpolated
+    /// logarithmically like Pose::eased_toward. Returns None if there are no keyframes.
+    pub fn evaluate(&self, time: f32) -> Option<CameraState> {
+        if self.keyframes.is_empty() {
+            return None;
+        }
+        let t = time / SECONDS_PER_KEYFRAME;
+        let spline = |field: fn(&CameraState) -> Vec3| {
+            let points: Vec<Vec3> = self.keyframes.iter().map(field).collect();
+            catmull_rom(&points, t)
+        };
+
+        Some(CameraState {
+            rotation: spline(|state| state.rotation.extend(0.0)).xy(),
+            zoom: spline(|state| Vec3::splat(state.zoom.ln())).x.exp(),
+            pan: spline(|state| state.pan.extend(0.0)).xy(),
+            focus: spline(|state| state.focus),
+        })
+    }
+}
+
+pub struct Camera {
+    pose: Pose,
+    goal: Pose, // Input and commands move the goal, and the pose follows it
+    smoothing: f32,
+    pan_anchor: Option<Vec2>,
+    held_keys: HashSet<KeyCode>,
+    lens: Option<Lens>, // None for an orthographic projection
+    path: CameraPath,
+    tour_time: f32, // Seconds into the path, while touring
+    // The gizmo's axes, with the matrix and window height that they were expanded for.
+    gizmo: Option<(Mat4, usize, Vec<Mesh>)>,
+}
+
+impl Camera {
+    pub fn new() -> Self {
+        Self {
+            pose: Pose::DEFAULT,
+            goal: Pose::DEFAULT,
+            smoothing: 0.0,
+            pan_anchor: None,
+            held_keys: HashSet::new(),
+            lens: None,
+            path: CameraPath::default(),
+            tour_time: 0.0,
+            gizmo: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.goal = Pose::DEFAULT;
+    }
+
+    /// Where the camera is heading, so that a saved state doesn't catch it mid-ease.
+    pub fn state(&self) -> CameraState {
+        self.goal.state()
+    }
+
+    /// Jumps to the state without easing.
+    pub fn set_state(&mut self, state: &CameraState) {
+        self.goal = Pose {
+            rotation: state.rotation,
+            zoom: state.zoom.clamp(MIN_ZOOM, MAX_ZOOM),
+            pan: state.pan,
+            focus: state.focus,
+        };
+        self.pose = self.goal;
+    }
+
+    pub fn update(&mut self, events: &VecDeque<Event>, dt: f32, editor: &EditorState) {
+        self.lens = editor.perspective.then_some(editor.lens);
+        self.smoothing = editor.camera_smoothing;
+
+        if editor.should_reload {
+            self.set_state(&CameraState::load());
+        }
+
+        if editor.should_clear_path {
+            self.path.clear();
+        }
+        if editor.should_add_keyframe {
+            self.path.push(self.state());
+        }
+
+        if editor.should_reset_camera || events.contains(&Event::KeyPressed(KeyCode::Home)) {
+            self.reset();
+        }
+
+        // Key events aren't consumed here, because other systems may also want to see them.
+        let mut analog_orbit = Vec2::ZERO;
+        let mut analog_zoom = 0.0;
+
+        for event in events {
+            match event {
+                Event::KeyPressed(code) => {
+                    self.held_keys.insert(*code);
+                }
+                Event::KeyReleased(code) => {
+                    self.held_keys.remove(code);
+                }
+                Event::MiddleClickPressed(pos) => self.pan_anchor = Some(*pos),
+                Event::MiddleClickReleased(_) => self.pan_anchor = None,
+                Event::MousePos(pos) => {
+                    if let Some(anchor) = self.pan_anchor {
+                        // Dragged panning isn't eased, so the scene stays under the mouse.
+                        self.goal.pan += *pos - anchor;
+                        self.pose.pan = self.goal.pan;
+                        self.pan_anchor = Some(*pos);
+                    }
+                }
+                Event::MouseDelta(delta) => {
+                    self.goal.rotation += Vec2::new(delta.x, -delta.y) * MOUSE_ORBIT_SPEED;
+                }
+                Event::Orbit(orbit) => analog_orbit += *orbit,
+                Event::Zoom(zoom) => analog_zoom += *zoom,
+                _ => (),
+            }
+        }
+
+        let held = |code| self.held_keys.contains(&code) as i32 as f32;
+
+        let orbit = Vec2::new(
+            held(KeyCode::KeyD) - held(KeyCode::KeyA),
+            held(KeyCode::KeyW) - held(KeyCode::KeyS),
+        ) + analog_orbit;
+        self.goal.rotation += orbit * ORBIT_SPEED * dt;
+
+        let zoom_direction = held(KeyCode::KeyE) - held(KeyCode::KeyQ) + analog_zoom;
+        self.goal.zoom *= (zoom_direction * ZOOM_SPEED * dt).exp();
+        self.goal.zoom = self.goal.zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+
+        // A tour overrides the input, which was still handled above to keep the held keys
+        // current. It loops back to the start when it reaches the end.
+        if editor.touring {
+            if let Some(state) = self.path.evaluate(self.tour_time) {
+                self.set_state(&state);
+                let duration = self.path.duration();
+                self.tour_time = if duration > 0.0 {
+                    (self.tour_time + dt) % duration
+                } else {
+                    0.0
+                };
+                return;
+            }
+        } else {
+            self.tour_time = 0.0;
+        }
+
+        self.pose = self
+            .pose
+            .eased_toward(&self.goal, easing_fraction(self.smoothing, dt));
+    }
+
+    /// Wraps a transform that places the scene in normalized coordinates. Panning happens in
+    /// normalized coordinates so that it tracks the mouse, while orbiting happens around the
+    /// focus in the scene's own space.
+    pub fn transform(&self, scene: &Mat4) -> Mat4 {
+        self.pose.transform(scene, self.lens.as_ref())
+    }
+
+    /// Draws the scene's X, Y and Z axes in red, green and blue in the bottom left corner, rotated
+    /// like the scene but without its pan, zoom or projection. Depth testing is disabled so that
+    /// the scene never hides it.
+    pub fn render_gizmo(&mut self, gpu: &mut Gpu) {
+        // Keep the rotated axes inside the clip volume's depth range, close to the near plane so
+        // the shader's depth darkening barely affects them.
+        let corner = Vec3::new(
+            -gpu.aspect_ratio() + GIZMO_SIZE + GIZMO_MARGIN,
+            -1.0 + GIZMO_SIZE + GIZMO_MARGIN,
+            GIZMO_SIZE,
+        );
+        let matrix = Mat4::from_translation(corner)
+            * Mat4::from_scale(Vec3::splat(GIZMO_SIZE))
+            * self.pose.rotation_matrix();
+
+        // The thick lines are expanded through the matrix and sized in pixels, so they're only
+        // rebuilt when the rotation or the window changes.
+        let height = gpu.height();
+        let is_current = matches!(&self.gizmo, Some((m, h, _)) if *m == matrix && *h == height);
+        if !is_current {
+            let axes = [
+                (Vec3::X, Vec4::new(1.0, 0.0, 0.0, 1.0)),
+                (Vec3::Y, Vec4::new(0.0, 1.0, 0.0, 1.0)),
+                (Vec3::Z, Vec4::new(0.0, 0.0, 1.0, 1.0)),
+            ];
+            let meshes = axes
+                .iter()
+                .map(|(axis, color)| {
+                    let segment = [(Vec3::ZERO, *axis)];
+                    Mesh::new_thick_lines(&segment, GIZMO_LINE_WIDTH, *color, &matrix, gpu)
+                })
+                .collect();
+            self.gizmo = Some((matrix, height, meshes));
+        }
+
+        gpu.depth_test(false);
+        let (_, _, meshes) = self.gizmo.as_ref().unwrap();
+        for mesh in meshes {
+            gpu.render_mesh(mesh, &Mat4::IDENTITY, None);
+        }
+    }
+
+    /// Returns the origin and direction of the ray under a point in normalized coordinates, in
+    /// the scene's own space. This works for both projections because it unprojects through
+    /// whichever transform is active.
+    pub fn ray(&self, scene: &Mat4, normalized_pos: Vec2) -> (Vec3, Vec3) {
+        let inverse = self.transform(scene).inverse();
+        let near = inverse.project_point3(normalized_pos.extend(0.0));
+        let far = inverse.project_point3(normalized_pos.extend(1.0));
+        (near, (far - near).normalize())
+    }
+
+    /// Eases toward orbiting the center of the bounds, zoomed so that they fill the viewport,
+    /// keeping the current rotation.
+    pub fn frame(&mut self, bounds: &Aabb, scene: &Mat4, aspect_ratio: f32) {
+        self.goal.focus = bounds.center();
+
+        // Fit the bounding sphere so that the bounds fit at any rotation.
+        let radius = bounds.size().length() / 2.0;
+        let viewport_half_extent = aspect_ratio.min(1.0) * FRAME_MARGIN;
+
+        self.goal.zoom = if let Some(lens) = &self.lens {
+            let half_fov = ((lens.fov() / 2.0).tan() * viewport_half_extent).atan();
+            let distance = radius / half_fov.sin();
+            PERSPECTIVE_DISTANCE / distance
+        } else {
+            let scene_scale = scene.x_axis.length();
+            viewport_half_extent / (radius * scene_scale)
+        };
+        self.goal.zoom = self.goal.zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+
+        // Pan so that the focus lands in the middle of the viewport.
+        self.goal.pan = Vec2::ZERO;
+        let goal_transform = self.goal.transform(scene, self.lens.as_ref());
+        self.goal.pan = -goal_transform.project_point3(self.goal.focus).xy();
+    }
+}
+
+/// The fraction of the remaining distance to ease in a frame, independent of the frame rate.
+/// Smoothing is the time in seconds to cover about 63% of the distance, and 0 snaps instantly.
+fn easing_fraction(smoothing: f32, dt: f32) -> f32 {
+    if smoothing <= 0.0 {
+        1.0
+    } else {
+        1.0 - (-dt / smoothing).exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_camera_state() {
+        let mut camera = Camera::new();
+        let state = CameraState {
+            rotation: Vec2::new(0.5, -0.25),
+            zoom: 2.0,
+            pan: Vec2::new(0.1, 0.2),
+            focus: Vec3::ONE,
+        };
+        camera.set_state(&state);
+        assert_eq!(camera.state(), state);
+
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(serde_json::from_str::<CameraState>(&json).unwrap(), state);
+
+        // Saves from before focus existed still load.
+        let old: CameraState =
+            serde_json::from_str(r#"{"rotation":[0.5,-0.25],"zoom":2.0}"#).unwrap();
+        assert_eq!(old.zoom, 2.0);
+        assert_eq!(old.focus, Vec3::ZERO);
+    }
+
+    #[test]
+    fn test_camera_path() {
+        let mut path = CameraPath::default();
+        assert!(path.evaluate(0.0).is_none());
+
+        let keyframes = [
+            CameraState::default(),
+            CameraState {
+                rotation: Vec2::new(1.0, 0.5),
+                zoom: 4.0,
+                pan: Vec2::X,
+                focus: Vec3::ONE,
+            },
+        ];
+        for keyframe in keyframes {
+            path.push(keyframe);
+        }
+        assert_eq!(path.duration(), SECONDS_PER_KEYFRAME);
+
+        // The path passes through its keyframes, and zoom is interpolated logarithmically.
+        let end = path.evaluate(path.duration()).unwrap();
+        assert!(end.focus.distance(Vec3::ONE) < 0.0001);
+        assert!((end.zoom - 4.0).abs() < 0.0001);
+        let middle = path.evaluate(path.duration() / 2.0).unwrap();
+        assert!((middle.zoom - 2.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_lens_guards() {
+        let lens = Lens {
+            fov_degrees: 180.0,
+            near: 5.0,
+            far: 1.0,
+        };
+        assert!(lens.fov() < std::f32::consts::PI);
+        let (near, far) = lens.planes();
+        assert!(near < far);
+        assert!(lens.projection().is_finite());
+    }
+
+    #[test]
+    fn test_easing_fraction() {
+        assert_eq!(easing_fraction(0.0, 0.016), 1.0);
+
+        // Two half frames ease as far as one whole frame.
+        let whole = easing_fraction(0.1, 0.02);
+        let half = easing_fraction(0.1, 0.01);
+        let remaining_after_halves = (1.0 - half) * (1.0 - half);
+        assert!((1.0 - whole - remaining_after_halves).abs() < 0.0001);
+    }
+}
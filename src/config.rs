@@ -0,0 +1,77 @@
+use crate::grid::CAN_SAVE;
+use crate::{WINDOW_HEIGHT, WINDOW_WIDTH};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+const CONFIG_PATH: &str = "config.toml";
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresentMode {
+    Fifo,
+    Mailbox,
+    Immediate,
+}
+
+/// Startup options, read from config.toml if it exists. Missing fields take their defaults.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub window_width: u32, // In logical pixels
+    pub window_height: u32,
+    pub fullscreen: bool,
+    pub vsync: bool,
+    /// Overrides vsync with a specific present mode if the surface supports it.
+    pub present_mode: Option<PresentMode>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            window_width: WINDOW_WIDTH,
+            window_height: WINDOW_HEIGHT,
+            fullscreen: false,
+            vsync: true,
+            present_mode: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let contents = match fs::read_to_string(CONFIG_PATH) {
+            Ok(contents) => contents,
+            Err(_) => return Self::default(),
+        };
+
+        match toml::from_str(&contents) {
+            Ok(config) => config,
+            Err(e) => {
+                log::warn!("Ignoring invalid {}: {}", CONFIG_PATH, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        if !CAN_SAVE {
+            return;
+        }
+        let result = toml::to_string_pretty(self)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| fs::write(CONFIG_PATH, contents).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::warn!("Failed to save {}: {}", CONFIG_PATH, e);
+        }
+    }
+
+    pub fn wgpu_present_mode(&self) -> wgpu::PresentMode {
+        match self.present_mode {
+            Some(PresentMode::Fifo) => wgpu::PresentMode::Fifo,
+            Some(PresentMode::Mailbox) => wgpu::PresentMode::Mailbox,
+            Some(PresentMode::Immediate) => wgpu::PresentMode::Immediate,
+            None if self.vsync => wgpu::PresentMode::AutoVsync,
+            None => wgpu::PresentMode::AutoNoVsync,
+        }
+    }
+}
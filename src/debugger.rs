@@ -1,10 +1,19 @@
-use crate::grid::{Atom, EditorState};
+use crate::gpu::WrapMode;
+use crate::grid::{Atom, Axis, EditorState, ViewMode, GRID_SIZE};
 use crate::math::transform_2d;
 use crate::prelude::*;
 use egui::epaint::{image::ImageData, textures::*};
 use egui::{self, Modifiers};
+use glam::IVec2;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
 
-// TODO: I'm not clipping the primitives as instructed.
+const FRAME_TIME_SAMPLES: usize = 60;
+const FRAME_TIME_BUDGET: f32 = 1.0 / 60.0;
+const TOGGLE_FPS_KEY: KeyCode = KeyCode::F3;
+const FRAME_TIMES_CSV_PATH: &str = "nopush/frame_times.csv";
+const FRAME_TIMES_RECORDING_PATH: &str = "nopush/frame_times_recording.csv";
+const FRAME_TIMES_CSV_HEADER: &str = "timestamp_s,frame_ms\n";
 
 #[derive(Default)]
 pub struct Debugger {
@@ -12,24 +21,204 @@ pub struct Debugger {
     egui_to_gpu_tex_id: HashMap<u64, usize>,
     mesh: Option<Mesh>,
     delta_times: VecDeque<f32>,
+    sample_times: VecDeque<f32>, // Seconds since launch for each entry in delta_times
+    elapsed_time: f32,
+    recording: Option<File>, // Appends every frame's timings while Some
     input: egui::RawInput,
     matrix: Mat4,
     full_output: egui::FullOutput,
+    modifiers: egui::Modifiers,
     pub editor_state: EditorState,
+    /// The cell under the cursor, its center in normalized coordinates and its atom, labelled
+    /// next frame.
+    pub hovered_cell: Option<((usize, usize), Vec2, Atom)>,
+    pub total_pressure: f32, // The grid's, shown to check that the simulation conserves it
+}
+
+struct FrameTimeStats {
+    min: f32,
+    avg: f32,
+    p95: f32,
+    max: f32,
+}
+
+impl FrameTimeStats {
+    fn new(delta_times: &VecDeque<f32>) -> Self {
+        let mut sorted: Vec<f32> = delta_times.iter().copied().collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+
+        let p95_index = ((sorted.len() as f32 * 0.95).ceil() as usize).saturating_sub(1);
+
+        Self {
+            min: sorted[0],
+            avg: sorted.iter().sum::<f32>() / sorted.len() as f32,
+            p95: sorted[p95_index],
+            max: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+fn egui_modifiers(state: &ModifiersState) -> egui::Modifiers {
+    egui::Modifiers {
+        alt: state.alt_key(),
+        ctrl: state.control_key(),
+        shift: state.shift_key(),
+        mac_cmd: cfg!(target_os = "macos") && state.super_key(),
+        command: if cfg!(target_os = "macos") {
+            state.super_key()
+        } else {
+            state.control_key()
+        },
+    }
+}
+
+fn egui_key(code: KeyCode) -> Option<egui::Key> {
+    use egui::Key;
+    let key = match code {
+        KeyCode::ArrowDown => Key::ArrowDown,
+        KeyCode::ArrowLeft => Key::ArrowLeft,
+        KeyCode::ArrowRight => Key::ArrowRight,
+        KeyCode::ArrowUp => Key::ArrowUp,
+        KeyCode::Escape => Key::Escape,
+        KeyCode::Tab => Key::Tab,
+        KeyCode::Backspace => Key::Backspace,
+        KeyCode::Enter | KeyCode::NumpadEnter => Key::Enter,
+        KeyCode::Space => Key::Space,
+        KeyCode::Insert => Key::Insert,
+        KeyCode::Delete => Key::Delete,
+        KeyCode::Home => Key::Home,
+        KeyCode::End => Key::End,
+        KeyCode::PageUp => Key::PageUp,
+        KeyCode::PageDown => Key::PageDown,
+        KeyCode::Minus | KeyCode::NumpadSubtract => Key::Minus,
+        KeyCode::Equal => Key::Equals,
+        KeyCode::Period | KeyCode::NumpadDecimal => Key::Period,
+        KeyCode::Comma => Key::Comma,
+        KeyCode::Digit0 | KeyCode::Numpad0 => Key::Num0,
+        KeyCode::Digit1 | KeyCode::Numpad1 => Key::Num1,
+        KeyCode::Digit2 | KeyCode::Numpad2 => Key::Num2,
+        KeyCode::Digit3 | KeyCode::Numpad3 => Key::Num3,
+        KeyCode::Digit4 | KeyCode::Numpad4 => Key::Num4,
+        KeyCode::Digit5 | KeyCode::Numpad5 => Key::Num5,
+        KeyCode::Digit6 | KeyCode::Numpad6 => Key::Num6,
+        KeyCode::Digit7 | KeyCode::Numpad7 => Key::Num7,
+        KeyCode::Digit8 | KeyCode::Numpad8 => Key::Num8,
+        KeyCode::Digit9 | KeyCode::Numpad9 => Key::Num9,
+        // Typed text arrives as Event::Text, so letters are only needed for shortcuts.
+        KeyCode::KeyA => Key::A,
+        KeyCode::KeyC => Key::C,
+        KeyCode::KeyV => Key::V,
+        KeyCode::KeyX => Key::X,
+        KeyCode::KeyY => Key::Y,
+        KeyCode::KeyZ => Key::Z,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Formats a CSV row with a timestamp in seconds and the frame time in milliseconds.
+fn frame_time_csv_row(time: f32, dt: f32) -> String {
+    format!("{:.6},{:.3}\n", time, dt * 1000.0)
+}
+
+fn frame_times_csv(sample_times: &VecDeque<f32>, delta_times: &VecDeque<f32>) -> String {
+    let mut csv = String::from(FRAME_TIMES_CSV_HEADER);
+    for (time, dt) in sample_times.iter().zip(delta_times) {
+        csv += &frame_time_csv_row(*time, *dt);
+    }
+    csv
 }
 
 impl Debugger {
-    fn max_dt(delta_times: &VecDeque<f32>) -> f32 {
-        *delta_times
+    /// Writes the frame times currently held for the graph to a CSV file.
+    pub fn export_frame_times(&self) -> std::io::Result<()> {
+        let csv = frame_times_csv(&self.sample_times, &self.delta_times);
+        File::create(FRAME_TIMES_CSV_PATH)?.write_all(csv.as_bytes())?;
+        log::info!("Frame times exported to {}", FRAME_TIMES_CSV_PATH);
+        Ok(())
+    }
+
+    /// Starts or stops appending every frame's timings to a CSV file, which isn't limited to the
+    /// samples the graph keeps.
+    pub fn set_recording(&mut self, recording: bool) -> std::io::Result<()> {
+        if !recording {
+            self.recording = None;
+            return Ok(());
+        }
+        if self.recording.is_some() {
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(FRAME_TIMES_RECORDING_PATH)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(FRAME_TIMES_CSV_HEADER.as_bytes())?;
+        }
+        self.recording = Some(file);
+        log::info!("Recording frame times to {}", FRAME_TIMES_RECORDING_PATH);
+        Ok(())
+    }
+
+    fn record_frame_time(&mut self, dt: f32) {
+        self.elapsed_time += dt;
+
+        self.delta_times.push_back(dt);
+        self.sample_times.push_back(self.elapsed_time);
+        if self.delta_times.len() > FRAME_TIME_SAMPLES {
+            self.delta_times.pop_front();
+            self.sample_times.pop_front();
+        }
+
+        if let Some(file) = &mut self.recording {
+            let row = frame_time_csv_row(self.elapsed_time, dt);
+            if let Err(e) = file.write_all(row.as_bytes()) {
+                log::warn!("Stopped recording frame times: {}", e);
+                self.recording = None;
+            }
+        }
+    }
+
+    /// Draws the recent frame times as a sparkline, with segments over the frame budget in red.
+    fn frame_time_graph(ui: &mut egui::Ui, delta_times: &VecDeque<f32>) {
+        let size = egui::vec2(
+            FRAME_TIME_SAMPLES as f32 * 2.0,
+            ui.spacing().interact_size.y,
+        );
+        let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+        let rect = response.rect;
+
+        // Show up to twice the budget so that the budget line sits in the middle.
+        let max_graphed_dt = FRAME_TIME_BUDGET * 2.0;
+        let dt_to_y = |dt: f32| rect.bottom() - rect.height() * (dt / max_graphed_dt).min(1.0);
+
+        painter.rect_filled(rect, 0.0, egui::Color32::from_black_alpha(128));
+        painter.hline(
+            rect.x_range(),
+            dt_to_y(FRAME_TIME_BUDGET),
+            egui::Stroke::new(1.0, egui::Color32::GRAY),
+        );
+
+        let points: Vec<(egui::Pos2, f32)> = delta_times
             .iter()
-            .max_by(|a, b| {
-                if a < b {
-                    std::cmp::Ordering::Less
-                } else {
-                    std::cmp::Ordering::Greater
-                }
+            .enumerate()
+            .map(|(i, dt)| {
+                let x = rect.left() + rect.width() * i as f32 / (FRAME_TIME_SAMPLES - 1) as f32;
+                (egui::pos2(x, dt_to_y(*dt)), *dt)
             })
-            .unwrap()
+            .collect();
+
+        for pair in points.windows(2) {
+            let (a, a_dt) = pair[0];
+            let (b, b_dt) = pair[1];
+            let color = if a_dt.max(b_dt) > FRAME_TIME_BUDGET {
+                egui::Color32::RED
+            } else {
+                egui::Color32::GREEN
+            };
+            painter.line_segment([a, b], egui::Stroke::new(1.0, color));
+        }
     }
 
     pub fn render_test(&mut self, gpu: &mut Gpu) {
@@ -65,6 +254,10 @@ impl Debugger {
     }
 
     pub fn update(&mut self, events: &mut VecDeque<Event>, dt: f32, gpu: &Gpu) {
+        if events.contains(&Event::KeyPressed(TOGGLE_FPS_KEY)) {
+            self.editor_state.show_fps = !self.editor_state.show_fps;
+        }
+
         events.retain(|event| {
             match event {
                 Event::LeftClickPressed(pos) => {
@@ -74,7 +267,7 @@ impl Debugger {
                         pos: mouse_egui,
                         button: egui::PointerButton::Primary,
                         pressed: true,
-                        modifiers: egui::Modifiers::default(),
+                        modifiers: self.modifiers,
                     });
                 }
                 Event::LeftClickReleased(pos) => {
@@ -84,7 +277,7 @@ impl Debugger {
                         pos: mouse_egui,
                         button: egui::PointerButton::Primary,
                         pressed: false,
-                        modifiers: egui::Modifiers::default(),
+                        modifiers: self.modifiers,
                     });
                 }
                 Event::MousePos(pos) => {
@@ -94,11 +287,41 @@ impl Debugger {
                         .events
                         .push(egui::Event::PointerMoved(mouse_egui));
                 }
+                Event::Scroll(delta) => {
+                    self.input.events.push(egui::Event::MouseWheel {
+                        unit: egui::MouseWheelUnit::Line,
+                        delta: egui::vec2(delta.x, delta.y),
+                        modifiers: self.modifiers,
+                    });
+                }
+                Event::KeyPressed(code) | Event::KeyReleased(code) => {
+                    if let Some(key) = egui_key(*code) {
+                        self.input.events.push(egui::Event::Key {
+                            key,
+                            physical_key: Some(key),
+                            pressed: matches!(event, Event::KeyPressed(_)),
+                            repeat: false,
+                            modifiers: self.modifiers,
+                        });
+                    }
+                }
+                Event::Text(text) => {
+                    self.input.events.push(egui::Event::Text(text.clone()));
+                }
+                Event::ModifiersChanged(state) => {
+                    self.modifiers = egui_modifiers(state);
+                    self.input.modifiers = self.modifiers;
+                }
                 _ => (),
             }
 
-            // Remove pointer events (return false) if the egui context wants them.
-            !self.ctx.wants_pointer_input()
+            // Remove events (return false) if the egui context wants them.
+            match event {
+                // Releases are always passed on so that nothing thinks a key is stuck down.
+                Event::KeyReleased(_) | Event::ModifiersChanged(_) => true,
+                Event::KeyPressed(_) | Event::Text(_) => !self.ctx.wants_keyboard_input(),
+                _ => !self.ctx.wants_pointer_input(),
+            }
         });
 
         self.ctx.set_pixels_per_point(2.0); // TODO: customise this based on window height?
@@ -112,20 +335,74 @@ impl Debugger {
                 ))
         };
 
+        self.record_frame_time(dt);
+
+        // Keeps egui's font atlas within what create_texture accepts.
+        self.input.max_texture_side = Some(gpu.max_texture_size() as usize);
+
+        // egui positions are in points, so the window's pixels are scaled down to match.
+        let hovered_cell_label = self.hovered_cell.map(|((x, y), normalized_pos, atom)| {
+            let window_pos = gpu.normalized_to_window(&normalized_pos);
+            let pos = window_pos / self.ctx.pixels_per_point();
+            let atom = match atom {
+                Atom::Gas(pressure) => format!("Gas, pressure {:.2}", pressure),
+                Atom::Solid => "Solid".to_string(),
+                Atom::Liquid => "Liquid".to_string(),
+            };
+            (
+                egui::pos2(pos.x, pos.y),
+                format!("({}, {})\n{}", x, y, atom),
+            )
+        });
+
+        // The file operations need all of self, so they happen after the UI closure.
+        let mut should_export = false;
+        let mut recording = self.recording.is_some();
+
         self.full_output = self.ctx.run(std::mem::take(&mut self.input), |ctx| {
             egui::TopBottomPanel::top("top panel").show(&ctx, |ui| {
                 ui.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
                     // TODO: Update the displayed time every second instead of every 60 frames.
                     // TODO: also, switch to processing time.
-                    self.delta_times.push_back(dt);
-                    if self.delta_times.len() > 60 {
-                        self.delta_times.pop_front();
+                    let stats = FrameTimeStats::new(&self.delta_times);
+                    ui.label(format!(
+                        "Frame min: {:.1}ms  avg: {:.1}ms  p95: {:.1}ms  max: {:.1}ms",
+                        stats.min * 1000.0,
+                        stats.avg * 1000.0,
+                        stats.p95 * 1000.0,
+                        stats.max * 1000.0
+                    ));
+
+                    Self::frame_time_graph(ui, &self.delta_times);
+
+                    if let Some(gpu_frame_time) = gpu.frame_time() {
+                        ui.label(format!("GPU: {:.2}ms", gpu_frame_time * 1000.0));
                     }
 
-                    let max_dt = Self::max_dt(&self.delta_times);
-                    ui.label(format!("Worst frame: {:.1}ms", max_dt * 1000.0));
+                    ui.label(format!("Commands: {}", gpu.command_count()));
+
+                    should_export = ui.button("Export CSV").clicked();
+                    ui.checkbox(&mut recording, "Record");
                 });
             });
+            if self.editor_state.show_fps {
+                let average_dt =
+                    self.delta_times.iter().sum::<f32>() / self.delta_times.len() as f32;
+                egui::Area::new(egui::Id::new("fps"))
+                    .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-8.0, 32.0))
+                    .interactable(false)
+                    .show(&ctx, |ui| {
+                        ui.label(format!("{:.0} FPS", 1.0 / average_dt));
+                    });
+            }
+            if let Some((pos, text)) = hovered_cell_label {
+                egui::Area::new(egui::Id::new("hovered cell"))
+                    .fixed_pos(pos + egui::vec2(8.0, 8.0))
+                    .interactable(false)
+                    .show(&ctx, |ui| {
+                        ui.label(text);
+                    });
+            }
             egui::Window::new("Editor").show(&ctx, |ui| {
                 ui.with_layout(egui::Layout::left_to_right(egui::Align::TOP), |ui| {
                     let radio_atom = self.editor_state.current_atom;
@@ -144,6 +421,25 @@ impl Debugger {
                 if let Atom::Gas(pressure) = &mut self.editor_state.current_atom {
                     ui.add(egui::Slider::new(pressure, -100.0..=100.0).text("Pressure"));
                 }
+                ui.add(egui::Slider::new(&mut self.editor_state.brush_radius, 0..=4).text("Brush"));
+
+                ui.with_layout(egui::Layout::left_to_right(egui::Align::TOP), |ui| {
+                    let mirror_axis = &mut self.editor_state.mirror_axis;
+                    ui.label("Mirror");
+                    ui.radio_value(mirror_axis, None, "Off");
+                    ui.radio_value(mirror_axis, Some(Axis::X), "X");
+                    ui.radio_value(mirror_axis, Some(Axis::Y), "Y");
+                });
+                if self.editor_state.mirror_axis.is_some() {
+                    ui.add(
+                        egui::Slider::new(
+                            &mut self.editor_state.mirror_plane,
+                            0.0..=GRID_SIZE as f32,
+                        )
+                        .step_by(0.5)
+                        .text("Mirror plane"),
+                    );
+                }
 
                 self.editor_state.should_reload = ui.button("Reload").clicked();
 
@@ -159,45 +455,211 @@ impl Debugger {
                 }
 
                 self.editor_state.should_step = ui.button("Step").clicked();
+
+                ui.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
+                    let editor = &mut self.editor_state;
+                    editor.should_mirror = None;
+                    for (label, axis) in [("Mirror X", Axis::X), ("Mirror Y", Axis::Y)] {
+                        if ui.button(label).clicked() {
+                            editor.should_mirror = Some(axis);
+                        }
+                    }
+                    editor.should_rotate = ui.button("Rotate").clicked();
+
+                    // Up in the 2D view is +y.
+                    editor.should_translate = None;
+                    for (label, offset) in [
+                        ("←", IVec2::NEG_X),
+                        ("→", IVec2::X),
+                        ("↑", IVec2::Y),
+                        ("↓", IVec2::NEG_Y),
+                    ] {
+                        if ui.button(label).clicked() {
+                            editor.should_translate = Some(offset);
+                        }
+                    }
+                });
+
+                ui.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
+                    self.editor_state.should_clear = ui.button("Clear").clicked();
+                    self.editor_state.should_randomize = ui.button("Randomize").clicked();
+                    ui.add(
+                        egui::Slider::new(&mut self.editor_state.solid_fraction, 0.0..=1.0)
+                            .text("Solid"),
+                    );
+                });
+                ui.label(format!("Total pressure: {:.2}", self.total_pressure));
+
+                ui.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
+                    ui.label("Seed");
+                    ui.add(egui::DragValue::new(&mut self.editor_state.seed));
+                    self.editor_state.should_reseed = ui.button("Reseed").clicked();
+                });
+
+                self.editor_state.should_reset_camera = ui.button("Reset camera").clicked();
+
+                ui.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
+                    self.editor_state.should_add_keyframe = ui.button("Add keyframe").clicked();
+                    self.editor_state.should_clear_path = ui.button("Clear path").clicked();
+                    ui.checkbox(&mut self.editor_state.touring, "Tour");
+                });
+
+                ui.with_layout(egui::Layout::left_to_right(egui::Align::TOP), |ui| {
+                    let view_mode = &mut self.editor_state.view_mode;
+                    ui.radio_value(view_mode, ViewMode::Both, "2D + 3D");
+                    ui.radio_value(view_mode, ViewMode::TwoD, "2D");
+                    ui.radio_value(view_mode, ViewMode::ThreeD, "3D");
+                    ui.radio_value(view_mode, ViewMode::SideBySide, "Side by side");
+                });
+
+                ui.checkbox(&mut self.editor_state.perspective, "Perspective");
+                if self.editor_state.perspective {
+                    let lens = &mut self.editor_state.lens;
+                    ui.add(egui::Slider::new(&mut lens.fov_degrees, 10.0..=120.0).text("FOV"));
+                    ui.add(
+                        egui::Slider::new(&mut lens.near, 0.01..=10.0)
+                            .logarithmic(true)
+                            .text("Near"),
+                    );
+                    ui.add(
+                        egui::Slider::new(&mut lens.far, 10.0..=1000.0)
+                            .logarithmic(true)
+                            .text("Far"),
+                    );
+                }
+                ui.add(
+                    egui::Slider::new(&mut self.editor_state.camera_smoothing, 0.0..=0.5)
+                        .text("Camera smoothing (s)"),
+                );
+
+                ui.checkbox(&mut self.editor_state.depth_prepass, "Depth prepass");
+                ui.checkbox(&mut self.editor_state.show_solids, "Cubes");
+                ui.checkbox(&mut self.editor_state.show_floor, "Floor");
+                ui.checkbox(&mut self.editor_state.show_bounds, "Bounds");
+                ui.checkbox(&mut self.editor_state.show_gas, "Gas");
+                ui.checkbox(
+                    &mut self.editor_state.show_pressure_spheres,
+                    "Pressure spheres",
+                );
+                if self.editor_state.show_pressure_spheres {
+                    ui.add(
+                        egui::Slider::new(&mut self.editor_state.pressure_scale, 0.1..=10.0)
+                            .logarithmic(true)
+                            .text("Sphere scale"),
+                    );
+                }
+                ui.checkbox(
+                    &mut self.editor_state.show_pressure_contour,
+                    "Pressure contour",
+                );
+                if self.editor_state.show_pressure_contour {
+                    ui.add(
+                        egui::Slider::new(&mut self.editor_state.contour_pressure, -100.0..=100.0)
+                            .text("Contour pressure"),
+                    );
+                }
+
+                ui.checkbox(
+                    &mut self.editor_state.auto_pressure_range,
+                    "Auto pressure range",
+                );
+                if !self.editor_state.auto_pressure_range {
+                    let (min, max) = &mut self.editor_state.pressure_range;
+                    ui.add(egui::Slider::new(min, -100.0..=100.0).text("Pressure min"));
+                    ui.add(egui::Slider::new(max, -100.0..=100.0).text("Pressure max"));
+                }
+
+                ui.add(
+                    egui::Slider::new(&mut self.editor_state.exposure_stops, -4.0..=4.0)
+                        .text("Exposure"),
+                );
+
+                ui.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
+                    ui.color_edit_button_rgb(self.editor_state.fog_color.as_mut());
+                    ui.add(
+                        egui::Slider::new(&mut self.editor_state.fog_density, 0.0..=4.0)
+                            .text("Fog"),
+                    );
+                });
             });
         });
+
+        if should_export {
+            if let Err(e) = self.export_frame_times() {
+                log::warn!("Failed to export frame times: {}", e);
+            }
+        }
+        if let Err(e) = self.set_recording(recording) {
+            log::warn!("Failed to record frame times: {}", e);
+        }
     }
 
-    pub fn render(&mut self, gpu: &mut Gpu) {
-        gpu.depth_test(false);
+    fn rgba_bytes(image: &ImageData) -> Vec<u8> {
+        let pixels: Vec<egui::Color32> = match image {
+            ImageData::Color(color_image) => color_image.pixels.clone(),
+            ImageData::Font(font_image) => font_image.srgba_pixels(None).collect(),
+        };
 
-        if !self.full_output.textures_delta.set.is_empty() {
-            assert_eq!(self.full_output.textures_delta.set.len(), 1);
-            let (egui_tex_id, delta) = &self.full_output.textures_delta.set[0];
-            assert_eq!(delta.options.magnification, TextureFilter::Linear);
-            assert_eq!(delta.options.minification, TextureFilter::Linear);
-            assert_eq!(delta.options.wrap_mode, TextureWrapMode::ClampToEdge);
-            assert_eq!(delta.pos, None);
-            let font_image = match &delta.image {
-                ImageData::Color(_) => panic!(),
-                ImageData::Font(f) => f,
-            };
+        let mut pixel_bytes = Vec::with_capacity(pixels.len() * 4);
+        for pixel in pixels {
+            pixel_bytes.extend_from_slice(&pixel.to_array());
+        }
+        pixel_bytes
+    }
 
-            let gpu_tex_id = gpu.create_texture(font_image.size[0], font_image.size[1], true);
-            let srgba_pixels = font_image.srgba_pixels(None);
-            let mut pixel_bytes = Vec::with_capacity(srgba_pixels.len() * 4);
-            for pixel in srgba_pixels {
-                pixel_bytes.push(pixel.r());
-                pixel_bytes.push(pixel.g());
-                pixel_bytes.push(pixel.b());
-                pixel_bytes.push(pixel.a());
-            }
-            gpu.write_rgba_texture(gpu_tex_id, &pixel_bytes);
+    pub fn render(&mut self, gpu: &mut Gpu) {
+        gpu.depth_test(false);
 
+        let textures_delta = std::mem::take(&mut self.full_output.textures_delta);
+        for (egui_tex_id, delta) in &textures_delta.set {
             let egui_tex_id = match egui_tex_id {
                 egui::TextureId::Managed(id) => *id,
-                _ => panic!(),
+                egui::TextureId::User(_) => panic!("User textures aren't supported"),
             };
-            assert!(egui_tex_id == 0);
 
-            self.egui_to_gpu_tex_id.insert(egui_tex_id, gpu_tex_id);
+            let width = delta.image.width();
+            let height = delta.image.height();
+            let pixel_bytes = Self::rgba_bytes(&delta.image);
+
+            match delta.pos {
+                None => {
+                    let linear_filtering = delta.options.magnification == TextureFilter::Linear;
+                    let gpu_tex_id = match gpu.create_texture(
+                        width,
+                        height,
+                        linear_filtering,
+                        WrapMode::Clamp,
+                        1,
+                    ) {
+                        Ok(id) => id,
+                        Err(e) => {
+                            log::warn!("Skipping egui texture: {}", e);
+                            continue;
+                        }
+                    };
+                    gpu.write_rgba_texture(gpu_tex_id, &pixel_bytes);
+                    // A full update of an existing texture replaces it.
+                    if let Some(old_gpu_tex_id) =
+                        self.egui_to_gpu_tex_id.insert(egui_tex_id, gpu_tex_id)
+                    {
+                        gpu.destroy_texture(old_gpu_tex_id);
+                    }
+                }
+                Some([x, y]) => {
+                    let Some(&gpu_tex_id) = self.egui_to_gpu_tex_id.get(&egui_tex_id) else {
+                        continue; // Its creation was skipped
+                    };
+                    gpu.write_rgba_subregion(
+                        gpu_tex_id,
+                        x as u32,
+                        y as u32,
+                        width as u32,
+                        height as u32,
+                        &pixel_bytes,
+                    );
+                }
+            }
         }
-        assert!(self.full_output.textures_delta.free.is_empty());
 
         let shapes = std::mem::take(&mut self.full_output.shapes);
         for prim in self
@@ -242,13 +704,73 @@ impl Debugger {
             let gpu_tex_id = *self.egui_to_gpu_tex_id.get(&egui_tex_id).unwrap();
             assert!(gpu_tex_id != 0);
 
+            // Convert the clip rect from egui points to window pixels via normalized coordinates.
+            let clip_corner_a = gpu.normalized_to_window(&transform_2d(
+                &Vec2::new(prim.clip_rect.min.x, prim.clip_rect.min.y),
+                &self.matrix,
+            ));
+            let clip_corner_b = gpu.normalized_to_window(&transform_2d(
+                &Vec2::new(prim.clip_rect.max.x, prim.clip_rect.max.y),
+                &self.matrix,
+            ));
+            let clip_min = clip_corner_a.min(clip_corner_b).max(Vec2::ZERO).round();
+            let clip_max = clip_corner_a.max(clip_corner_b).max(Vec2::ZERO).round();
+            let clip_size = clip_max - clip_min;
+            if clip_size.x < 1.0 || clip_size.y < 1.0 {
+                continue;
+            }
+
             let mesh = Mesh::new_2d(
                 &vert_positions,
                 Some(&vert_colors),
                 Some((gpu_tex_id, &vert_uvs)),
                 gpu,
             );
+
+            gpu.set_scissor(
+                clip_min.x as u32,
+                clip_min.y as u32,
+                clip_size.x as u32,
+                clip_size.y as u32,
+            );
             gpu.render_mesh(&mesh, &self.matrix, None);
         }
+        gpu.reset_scissor();
+
+        // egui frees textures after the frame that last used them has been rendered.
+        for egui_tex_id in &textures_delta.free {
+            let egui_tex_id = match egui_tex_id {
+                egui::TextureId::Managed(id) => *id,
+                egui::TextureId::User(_) => panic!("User textures aren't supported"),
+            };
+            if let Some(gpu_tex_id) = self.egui_to_gpu_tex_id.remove(&egui_tex_id) {
+                gpu.destroy_texture(gpu_tex_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_time_stats() {
+        let delta_times: VecDeque<f32> = (1..=100).map(|i| i as f32).collect();
+        let stats = FrameTimeStats::new(&delta_times);
+        assert_eq!(stats.min, 1.0);
+        assert_eq!(stats.avg, 50.5);
+        assert_eq!(stats.p95, 95.0);
+        assert_eq!(stats.max, 100.0);
+    }
+
+    #[test]
+    fn test_frame_times_csv() {
+        let sample_times: VecDeque<f32> = [0.5, 1.0].into();
+        let delta_times: VecDeque<f32> = [0.016, 0.5].into();
+        assert_eq!(
+            frame_times_csv(&sample_times, &delta_times),
+            "timestamp_s,frame_ms\n0.500000,16.000\n1.000000,500.000\n"
+        );
     }
 }
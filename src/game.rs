@@ -1,31 +1,216 @@
+use crate::camera::CameraState;
+#[cfg(feature = "gamepad")]
+use crate::gamepad::Gamepad;
+use crate::gpu::{FramePixels, Viewport};
 use crate::grid::*;
+use crate::input::{DoubleClickDetector, DragTracker, InputPlayback, InputRecording};
 use crate::prelude::*;
-use serde_json;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(target_arch = "wasm32")]
+use web_time::{SystemTime, UNIX_EPOCH};
+
+const SCREENSHOT_KEY: KeyCode = KeyCode::F2;
+const RECORD_KEY: KeyCode = KeyCode::F5;
+const PLAYBACK_KEY: KeyCode = KeyCode::F6;
+const INPUT_RECORDING_PATH: &str = "nopush/input_recording.json";
+const SIM_TIMESTEP: f32 = 1.0 / 60.0; // Seconds of simulation per step
+const MAX_SIM_STEPS_PER_FRAME: u32 = 4; // Slow frames fall behind rather than stall further
+const MAX_DELTA_TIME: f32 = 0.1; // Longer frames, like stopping at a breakpoint, count as this
+
+fn save_screenshot(frame: &FramePixels) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = format!("nopush/screenshot_{}.png", timestamp);
+
+    match image::save_buffer(
+        &path,
+        &frame.rgba,
+        frame.width,
+        frame.height,
+        image::ExtendedColorType::Rgba8,
+    ) {
+        Ok(()) => log::info!("Screenshot saved to {}", path),
+        Err(e) => log::warn!("Failed to save screenshot: {}", e),
+    }
+}
+
+/// Turns variable frame times into a whole number of fixed simulation steps, carrying the
+/// leftover time into the next frame. Input recordings store the steps it returns, and playback
+/// uses those instead, because the frame times differ between runs.
+struct FixedTimestep {
+    accumulator: f32,
+}
+
+impl FixedTimestep {
+    fn new() -> Self {
+        Self { accumulator: 0.0 }
+    }
+
+    fn steps(&mut self, dt: f32) -> u32 {
+        self.accumulator += dt;
+        let steps = (self.accumulator / SIM_TIMESTEP).floor() as u32;
+        self.accumulator -= steps as f32 * SIM_TIMESTEP;
+
+        if steps > MAX_SIM_STEPS_PER_FRAME {
+            // Drop the backlog, otherwise each frame would have even more steps to catch up on.
+            self.accumulator = 0.0;
+            MAX_SIM_STEPS_PER_FRAME
+        } else {
+            steps
+        }
+    }
+
+    fn reset(&mut self) {
+        self.accumulator = 0.0;
+    }
+}
+
+/// Paints the current atom under left clicks and drags, consuming the mouse events it uses.
+fn paint(
+    grid: &mut Grid,
+    dragging_pos: &mut Option<Vec2>,
+    events: &mut VecDeque<Event>,
+    editor: &EditorState,
+) {
+    events.retain(|event| match event {
+        Event::MousePos(end) => {
+            if let Some(start) = *dragging_pos {
+                // TODO: This can currently be called multiple times per atom when dragging, so my dragging_pos should be a Option<(usize, usize)> instead.
+                grid.modify_under_path(&start, &end, editor);
+                *dragging_pos = Some(*end);
+            }
+            false
+        }
+        Event::LeftClickPressed(pos) => {
+            grid.modify_under_path(&pos, &pos, editor);
+            *dragging_pos = Some(*pos);
+            false
+        }
+        Event::LeftClickReleased(_) => {
+            *dragging_pos = None;
+            false
+        }
+        _ => true,
+    });
+}
+
+/// The 2D and 3D views' viewports when they're side by side, with the 2D view on the left.
+fn side_by_side_viewports(view_mode: ViewMode, gpu: &Gpu) -> Option<(Viewport, Viewport)> {
+    if view_mode != ViewMode::SideBySide {
+        return None;
+    }
+    let width = gpu.width() as u32;
+    let height = gpu.height() as u32;
+    let half = width / 2;
+    Some((
+        Viewport {
+            x: 0,
+            y: 0,
+            width: half,
+            height,
+        },
+        Viewport {
+            x: half,
+            y: 0,
+            width: width - half,
+            height,
+        },
+    ))
+}
+
+/// Advances the grid by the frame's simulation steps and returns how many it took. During
+/// playback the steps are the recorded ones, so that the grid steps as it did when recorded
+/// whatever the frame times are now.
+fn simulate(
+    grid: &mut Grid,
+    sim_timestep: &mut FixedTimestep,
+    editor: &EditorState,
+    dt: f32,
+    recorded_steps: Option<u32>,
+) -> u32 {
+    let steps = match recorded_steps {
+        Some(steps) => steps,
+        None if editor.is_playing => sim_timestep.steps(dt),
+        None => {
+            sim_timestep.reset();
+            editor.should_step as u32
+        }
+    };
+    grid.update(editor, steps);
+    steps
+}
 
 pub struct Game {
     debugger: Debugger,
-    launch_time: Instant,
-    prev_frame_start_time: Instant,
+    camera: Camera,
+    prev_frame_start_time: Option<Instant>, // None until the first frame
     grid: Grid,
     events_for_next_frame: VecDeque<Event>,
     dragging_pos: Option<Vec2>,
     previous_mouse_pos_for_deduplication: Vec2,
+    double_click: DoubleClickDetector,
+    drag: DragTracker,
+    sim_timestep: FixedTimestep,
+    input_frame: u64, // Frames since recording or playback started
+    recording: Option<InputRecording>,
+    playback: Option<InputPlayback>,
+    #[cfg(feature = "gamepad")]
+    gamepad: Gamepad,
 }
 
 impl Game {
     pub fn new() -> Game {
+        let mut debugger = Debugger::default();
+        debugger.editor_state = EditorState::load();
+
+        let mut grid = Grid::load();
+        grid.reseed(debugger.editor_state.seed);
+
+        let mut camera = Camera::new();
+        camera.set_state(&CameraState::load());
+
         Self {
-            debugger: Debugger::default(),
-            launch_time: Instant::now(),
-            prev_frame_start_time: Instant::now(),
-            grid: Grid::load(),
+            debugger,
+            camera,
+            prev_frame_start_time: None,
+            grid,
             events_for_next_frame: VecDeque::new(),
             dragging_pos: None,
             previous_mouse_pos_for_deduplication: Vec2::new(0.0, 0.0),
+            double_click: DoubleClickDetector::new(),
+            drag: DragTracker::new(),
+            sim_timestep: FixedTimestep::new(),
+            input_frame: 0,
+            recording: None,
+            playback: None,
+            #[cfg(feature = "gamepad")]
+            gamepad: Gamepad::new(),
         }
     }
 
+    /// Queues live input for the next frame, recording it if a recording is in progress. While
+    /// a recording plays back, live input is ignored except for the key that stops playback.
     pub fn push_event(&mut self, event: Event) {
+        let is_control_key = matches!(
+            event,
+            Event::KeyPressed(RECORD_KEY | PLAYBACK_KEY)
+                | Event::KeyReleased(RECORD_KEY | PLAYBACK_KEY)
+        );
+        if self.playback.is_some() && !is_control_key {
+            return;
+        }
+        if let Some(recording) = &mut self.recording {
+            if !is_control_key {
+                recording.record(self.input_frame, event.clone());
+            }
+        }
+        self.queue_event(event);
+    }
+
+    fn queue_event(&mut self, event: Event) {
         let event = match event {
             Event::MousePos(pos) => {
                 if pos.distance(self.previous_mouse_pos_for_deduplication) > 0.0001 {
@@ -38,7 +223,25 @@ impl Game {
             _ => Some(event),
         };
         if let Some(event) = event {
+            let double_click_pos = match event {
+                Event::LeftClickPressed(pos) if self.double_click.press(pos, Instant::now()) => {
+                    Some(pos)
+                }
+                _ => None,
+            };
+
+            let gesture = self.drag.track(&event);
+
             self.events_for_next_frame.push_back(event);
+
+            if let Some(gesture) = gesture {
+                self.events_for_next_frame.push_back(gesture);
+            }
+
+            if let Some(pos) = double_click_pos {
+                self.events_for_next_frame
+                    .push_back(Event::DoubleClick(pos));
+            }
         }
     }
 
@@ -46,55 +249,367 @@ impl Game {
         &mut self,
         events: &mut VecDeque<Event>,
         editor: EditorState,
+        dt: f32,
         gpu: &mut Gpu,
     ) {
-        events.retain(|event| match event {
-            Event::MousePos(end) => {
-                if let Some(start) = self.dragging_pos {
-                    // TODO: This can currently be called multiple times per atom when dragging, so my dragging_pos should be a Option<(usize, usize)> instead.
-                    self.grid.modify_under_path(&start, &end, &editor);
-                    self.dragging_pos = Some(*end);
+        let shows_2d = editor.view_mode != ViewMode::ThreeD;
+        let shows_3d = editor.view_mode != ViewMode::TwoD;
+
+        let viewports = side_by_side_viewports(editor.view_mode, gpu);
+        self.grid.center_ortho_view(viewports.is_some());
+        let mouse_pos = self.previous_mouse_pos_for_deduplication;
+        let (mouse_pos_2d, mouse_pos_3d) = match &viewports {
+            Some((view_2d, view_3d)) => (
+                gpu.window_to_viewport(&mouse_pos, view_2d),
+                gpu.window_to_viewport(&mouse_pos, view_3d),
+            ),
+            None => (mouse_pos, mouse_pos),
+        };
+
+        if shows_2d {
+            // Painting consumes these events, so nothing else sees the remapped positions.
+            if let Some((view_2d, _)) = &viewports {
+                for event in events.iter_mut() {
+                    if let Event::MousePos(pos)
+                    | Event::LeftClickPressed(pos)
+                    | Event::LeftClickReleased(pos) = event
+                    {
+                        *pos = gpu.window_to_viewport(pos, view_2d);
+                    }
                 }
-                false
             }
-            Event::LeftClickPressed(pos) => {
-                self.grid.modify_under_path(&pos, &pos, &editor);
-                self.dragging_pos = Some(*pos);
-                false
+            paint(&mut self.grid, &mut self.dragging_pos, events, &editor);
+        } else {
+            self.dragging_pos = None;
+        }
+        self.debugger.hovered_cell = None;
+
+        let recorded_steps = self
+            .playback
+            .as_ref()
+            .map(|playback| playback.steps_for_frame(self.input_frame));
+        let steps = simulate(
+            &mut self.grid,
+            &mut self.sim_timestep,
+            &editor,
+            dt,
+            recorded_steps,
+        );
+        if let Some(recording) = &mut self.recording {
+            recording.record_steps(self.input_frame, steps);
+        }
+        self.debugger.total_pressure = self.grid.total_pressure();
+
+        if shows_2d {
+            if let Some((view_2d, _)) = viewports {
+                gpu.set_viewport(view_2d.x, view_2d.y, view_2d.width, view_2d.height);
+            }
+            self.grid.render_2d(gpu);
+            self.grid
+                .render_brush(mouse_pos_2d, editor.brush_radius, gpu);
+            if let Some(axis) = editor.mirror_axis {
+                self.grid
+                    .render_mirror_plane(axis, editor.mirror_plane, gpu);
             }
-            Event::LeftClickReleased(_) => {
-                self.dragging_pos = None;
-                false
+            if editor.show_pressure_contour {
+                self.grid
+                    .render_pressure_contour(editor.contour_pressure, gpu);
             }
-            _ => true,
-        });
+        }
+        if !shows_3d {
+            return;
+        }
 
-        self.grid.update(&editor);
-        self.grid.render_2d(gpu);
-        self.grid.render_ortho(gpu);
+        if let Some((_, view_3d)) = viewports {
+            gpu.set_viewport(view_3d.x, view_3d.y, view_3d.width, view_3d.height);
+        }
+        if editor.show_solids {
+            self.grid
+                .render_ortho(&self.camera, editor.depth_prepass, gpu);
+        }
+        if editor.show_floor {
+            self.grid.render_floor(&self.camera, gpu);
+        }
+        if editor.show_bounds {
+            self.grid.render_bounds(&self.camera, gpu);
+        }
+        if editor.show_gas {
+            self.grid.render_gas(&self.camera, gpu);
+        }
+        if editor.show_pressure_spheres {
+            self.grid
+                .render_pressure_spheres(&self.camera, editor.pressure_scale, gpu);
+        }
+        if let Some(cell) = self.grid.hovered_cell(&self.camera, mouse_pos_3d) {
+            self.grid.render_outline(&self.camera, cell, gpu);
+            let mut center = self.grid.cell_center(&self.camera, cell);
+            if let Some((_, view_3d)) = &viewports {
+                center = gpu.viewport_to_window(&center, view_3d);
+            }
+            self.debugger.hovered_cell = Some((cell, center, self.grid.inspect(cell)));
+        }
+        self.camera.render_gizmo(gpu);
     }
 
-    pub fn update_and_render(&mut self, gpu: &mut Gpu) {
-        gpu.begin_frame();
+    /// Does nothing if the frame is skipped, leaving the events for the next one. Returns an
+    /// error if the window can't be rendered to anymore.
+    pub fn update_and_render(&mut self, gpu: &mut Gpu) -> Result<(), wgpu::SurfaceError> {
+        if !gpu.begin_frame()? {
+            return Ok(());
+        }
 
         let frame_start_time = Instant::now();
-        let delta_time = (frame_start_time - self.prev_frame_start_time).as_secs_f32();
-        let total_time = (frame_start_time - self.launch_time).as_secs_f64();
+        let delta_time = match self.prev_frame_start_time {
+            Some(prev) => (frame_start_time - prev).as_secs_f32().min(MAX_DELTA_TIME),
+            None => SIM_TIMESTEP, // There's no previous frame to measure, so assume a typical one
+        };
+
+        #[cfg(feature = "gamepad")]
+        for event in self.gamepad.poll(self.previous_mouse_pos_for_deduplication) {
+            self.push_event(event);
+        }
+
+        // Finished playback is dropped after the grid update, which still needs the last
+        // frame's recorded steps.
+        if let Some(playback) = &mut self.playback {
+            let recorded_events = playback.events_for_frame(self.input_frame);
+            for event in recorded_events {
+                self.queue_event(event);
+            }
+        }
 
         let mut events = std::mem::take(&mut self.events_for_next_frame);
 
+        if events.contains(&Event::KeyPressed(RECORD_KEY)) {
+            self.toggle_recording();
+        }
+        if events.contains(&Event::KeyPressed(PLAYBACK_KEY)) {
+            self.toggle_playback();
+        }
+
         self.debugger.update(&mut events, delta_time, gpu);
 
-        self.update_and_render_grid(&mut events, self.debugger.editor_state, gpu);
+        self.camera
+            .update(&events, delta_time, &self.debugger.editor_state);
+
+        if events.contains(&Event::KeyPressed(KeyCode::KeyF)) {
+            let view_mode = self.debugger.editor_state.view_mode;
+            let aspect_ratio = match side_by_side_viewports(view_mode, gpu) {
+                Some((_, view_3d)) => view_3d.aspect_ratio(),
+                None => gpu.aspect_ratio(),
+            };
+            self.camera.frame(
+                &self.grid.occupied_bounds(),
+                &self.grid.ortho_transform(),
+                aspect_ratio,
+            );
+        }
+
+        if events.contains(&Event::KeyPressed(SCREENSHOT_KEY)) {
+            gpu.read_frame();
+        }
+
+        let editor = &self.debugger.editor_state;
+        gpu.set_exposure(editor.exposure_stops.exp2());
+        gpu.set_fog(editor.fog_color, editor.fog_density);
+        self.update_and_render_grid(&mut events, self.debugger.editor_state, delta_time, gpu);
+        gpu.reset_viewport();
 
+        if self
+            .playback
+            .as_ref()
+            .is_some_and(InputPlayback::is_finished)
+        {
+            log::info!("Input playback finished");
+            self.playback = None;
+        }
+
+        // The debugger UI isn't part of the scene, so it ignores exposure and fog.
+        gpu.set_exposure(1.0);
+        gpu.set_fog(Vec3::ZERO, 0.0);
         self.debugger.render(gpu);
         gpu.finish_frame();
-        self.prev_frame_start_time = frame_start_time;
+
+        if let Some(frame) = gpu.take_read_frame() {
+            save_screenshot(&frame);
+        }
+
+        self.prev_frame_start_time = Some(frame_start_time);
+        self.input_frame += 1;
+        Ok(())
+    }
+
+    fn toggle_recording(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            Self::save_recording(&recording);
+        } else if self.playback.is_none() {
+            log::info!("Recording input");
+            self.recording = Some(InputRecording::default());
+            self.input_frame = 0;
+        }
+    }
+
+    /// Replays the saved recording onto the grid as it currently is, so load the grid that the
+    /// recording started from first for the same result.
+    fn toggle_playback(&mut self) {
+        if self.playback.take().is_some() {
+            log::info!("Input playback stopped");
+        } else if self.recording.is_none() {
+            match InputRecording::load(INPUT_RECORDING_PATH) {
+                Ok(recording) => {
+                    log::info!("Playing back {}", INPUT_RECORDING_PATH);
+                    self.playback = Some(InputPlayback::new(recording));
+                    self.input_frame = 0;
+                }
+                Err(e) => log::warn!("Failed to load {}: {}", INPUT_RECORDING_PATH, e),
+            }
+        }
+    }
+
+    fn save_recording(recording: &InputRecording) {
+        match recording.save(INPUT_RECORDING_PATH) {
+            Ok(()) => log::info!("Input recording saved to {}", INPUT_RECORDING_PATH),
+            Err(e) => log::warn!("Failed to save input recording: {}", e),
+        }
     }
 }
 
 impl Drop for Game {
     fn drop(&mut self) {
+        if let Some(recording) = &self.recording {
+            Self::save_recording(recording);
+        }
         self.grid.save();
+        self.camera.state().save();
+        self.debugger.editor_state.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_timestep() {
+        let mut timestep = FixedTimestep::new();
+
+        // Frames at twice the step rate step every other frame.
+        let steps: Vec<u32> = (0..4).map(|_| timestep.steps(SIM_TIMESTEP * 0.6)).collect();
+        assert_eq!(steps, [0, 1, 0, 1]);
+
+        // A stall is capped, and the time beyond the cap is dropped.
+        assert_eq!(timestep.steps(1.0), MAX_SIM_STEPS_PER_FRAME);
+        assert_eq!(timestep.steps(0.0), 0);
+    }
+
+    #[test]
+    fn test_input_playback() {
+        let editor = EditorState {
+            current_atom: Atom::Solid,
+            ..Default::default()
+        };
+
+        // A click, and a drag along the grid's bottom row two frames later.
+        let frames = [
+            vec![
+                Event::LeftClickPressed(Vec2::new(0.55, 0.25)),
+                Event::LeftClickReleased(Vec2::new(0.55, 0.25)),
+            ],
+            vec![],
+            vec![
+                Event::LeftClickPressed(Vec2::new(0.05, 0.05)),
+                Event::MousePos(Vec2::new(0.35, 0.05)),
+                Event::LeftClickReleased(Vec2::new(0.35, 0.05)),
+            ],
+        ];
+
+        let mut recording = InputRecording::default();
+        for (frame, events) in frames.iter().enumerate() {
+            for event in events {
+                recording.record(frame as u64, event.clone());
+            }
+        }
+        assert_eq!(recording.frames.len(), 2);
+
+        let mut live_grid = Grid::new();
+        let mut dragging_pos = None;
+        for events in &frames {
+            let mut events = events.iter().cloned().collect();
+            paint(&mut live_grid, &mut dragging_pos, &mut events, &editor);
+        }
+
+        // Replay through serialization, like a recording loaded from a file.
+        let json = serde_json::to_string(&recording).unwrap();
+        let mut playback = InputPlayback::new(serde_json::from_str(&json).unwrap());
+        let mut replayed_grid = Grid::new();
+        let mut dragging_pos = None;
+        for frame in 0..frames.len() {
+            let mut events = playback.events_for_frame(frame as u64).into();
+            paint(&mut replayed_grid, &mut dragging_pos, &mut events, &editor);
+        }
+        assert!(playback.is_finished());
+
+        assert!(live_grid.atoms()[5][2] == Atom::Solid);
+        assert!(live_grid.atoms()[3][0] == Atom::Solid);
+        assert!(live_grid.atoms() == replayed_grid.atoms());
+    }
+
+    #[test]
+    fn test_replay_steps() {
+        let editor = EditorState {
+            current_atom: Atom::Gas(100.0),
+            is_playing: true,
+            ..Default::default()
+        };
+        // A click in the middle of the grid, away from the edges that erase gas.
+        let click = vec![
+            Event::LeftClickPressed(Vec2::new(0.45, 0.45)),
+            Event::LeftClickReleased(Vec2::new(0.45, 0.45)),
+        ];
+
+        // Uneven frame times, so that the frames take different numbers of steps.
+        let frame_times = [0.03, 0.01, 0.05, 0.0, 0.04, 0.02];
+        let mut recording = InputRecording::default();
+        let mut live_grid = Grid::new();
+        let mut timestep = FixedTimestep::new();
+        let mut dragging_pos = None;
+        let mut total_steps = 0;
+        for (frame, dt) in frame_times.iter().enumerate() {
+            let mut events: VecDeque<Event> = VecDeque::new();
+            if frame == 0 {
+                for event in &click {
+                    recording.record(0, event.clone());
+                }
+                events.extend(click.iter().cloned());
+            }
+            paint(&mut live_grid, &mut dragging_pos, &mut events, &editor);
+            let steps = simulate(&mut live_grid, &mut timestep, &editor, *dt, None);
+            recording.record_steps(frame as u64, steps);
+            total_steps += steps;
+        }
+        assert!(total_steps > 0);
+
+        // Replayed at a steady frame rate, the grid still takes the recorded steps.
+        let json = serde_json::to_string(&recording).unwrap();
+        let mut playback = InputPlayback::new(serde_json::from_str(&json).unwrap());
+        let mut replayed_grid = Grid::new();
+        let mut timestep = FixedTimestep::new();
+        let mut dragging_pos = None;
+        for frame in 0..frame_times.len() as u64 {
+            let mut events = playback.events_for_frame(frame).into();
+            paint(&mut replayed_grid, &mut dragging_pos, &mut events, &editor);
+            let recorded_steps = Some(playback.steps_for_frame(frame));
+            simulate(
+                &mut replayed_grid,
+                &mut timestep,
+                &editor,
+                SIM_TIMESTEP,
+                recorded_steps,
+            );
+        }
+        assert!(playback.is_finished());
+
+        assert!(live_grid.atoms() != Grid::new().atoms());
+        assert!(live_grid.atoms() == replayed_grid.atoms());
     }
 }
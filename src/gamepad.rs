@@ -0,0 +1,75 @@
+use crate::prelude::*;
+use gilrs::{Axis, Button, EventType, Gilrs};
+
+const STICK_DEADZONE: f32 = 0.15;
+const TRIGGER_DEADZONE: f32 = 0.05;
+
+pub struct Gamepad {
+    gilrs: Option<Gilrs>,
+}
+
+impl Gamepad {
+    pub fn new() -> Self {
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => Some(gilrs),
+            Err(e) => {
+                log::warn!("Gamepad support unavailable: {}", e);
+                None
+            }
+        };
+        Self { gilrs }
+    }
+
+    /// Rescales the axis so that it starts from zero at the edge of the deadzone.
+    fn deadzone(value: f32, deadzone: f32) -> f32 {
+        if value.abs() < deadzone {
+            0.0
+        } else {
+            (value - deadzone * value.signum()) / (1.0 - deadzone)
+        }
+    }
+
+    /// Translates the gamepad state into the same events the mouse and keyboard produce.
+    /// Face buttons act as the left mouse button at the current cursor position.
+    pub fn poll(&mut self, cursor_pos: Vec2) -> Vec<Event> {
+        let gilrs = match self.gilrs.as_mut() {
+            Some(g) => g,
+            None => return vec![],
+        };
+
+        let mut events = vec![];
+
+        while let Some(event) = gilrs.next_event() {
+            match event.event {
+                EventType::ButtonPressed(Button::South, _) => {
+                    events.push(Event::LeftClickPressed(cursor_pos));
+                }
+                EventType::ButtonReleased(Button::South, _) => {
+                    events.push(Event::LeftClickReleased(cursor_pos));
+                }
+                _ => (),
+            }
+        }
+
+        if let Some((_, gamepad)) = gilrs.gamepads().next() {
+            let orbit = Vec2::new(
+                Self::deadzone(gamepad.value(Axis::LeftStickX), STICK_DEADZONE),
+                Self::deadzone(gamepad.value(Axis::LeftStickY), STICK_DEADZONE),
+            );
+            if orbit != Vec2::ZERO {
+                events.push(Event::Orbit(orbit));
+            }
+
+            let trigger = |button| {
+                let value = gamepad.button_data(button).map_or(0.0, |data| data.value());
+                Self::deadzone(value, TRIGGER_DEADZONE)
+            };
+            let zoom = trigger(Button::RightTrigger2) - trigger(Button::LeftTrigger2);
+            if zoom != 0.0 {
+                events.push(Event::Zoom(zoom));
+            }
+        }
+
+        events
+    }
+}
@@ -0,0 +1,151 @@
+use crate::gpu::WrapMode;
+use crate::prelude::*;
+use glam::Mat3;
+use gltf::image::Format;
+use gltf::mesh::Mode;
+
+/// The first mesh of a glTF file as a triangle list, transformed by its node. Normals and UVs
+/// are only Some if every primitive has them.
+pub struct GltfModel {
+    pub positions: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    pub uvs: Option<Vec<Vec2>>,
+    pub base_color: Option<(usize, usize, Vec<u8>)>, // Width, height and 8bit RGBA pixels
+}
+
+/// Loads the first mesh in the file. Indexed primitives are expanded into a triangle list, and
+/// primitives that aren't triangle lists are skipped.
+pub fn load_gltf(path: &str) -> Result<GltfModel, gltf::Error> {
+    let (document, buffers, images) = gltf::import(path)?;
+
+    let mut model = GltfModel {
+        positions: vec![],
+        normals: Some(vec![]),
+        uvs: Some(vec![]),
+        base_color: None,
+    };
+
+    let Some(mesh) = document.meshes().next() else {
+        return Ok(model);
+    };
+    let transform = mesh_transform(&document, mesh.index()).unwrap_or(Mat4::IDENTITY);
+    let normal_transform = Mat3::from_mat4(transform).inverse().transpose();
+
+    for primitive in mesh.primitives() {
+        if primitive.mode() != Mode::Triangles {
+            log::warn!("Skipping a glTF primitive that isn't a triangle list");
+            continue;
+        }
+
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let Some(positions) = reader.read_positions() else {
+            continue;
+        };
+        let positions: Vec<[f32; 3]> = positions.collect();
+        let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(Iterator::collect);
+        let uvs: Option<Vec<[f32; 2]>> = reader
+            .read_tex_coords(0)
+            .map(|uvs| uvs.into_f32().collect());
+
+        let indices: Vec<usize> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+
+        for &i in &indices {
+            let position = Vec3::from_array(positions[i]);
+            model.positions.push(transform.transform_point3(position));
+        }
+
+        model.normals = match (model.normals.take(), &normals) {
+            (Some(mut all), Some(normals)) => {
+                for &i in &indices {
+                    let normal = normal_transform * Vec3::from_array(normals[i]);
+                    all.push(normal.normalize_or_zero());
+                }
+                Some(all)
+            }
+            _ => None,
+        };
+
+        model.uvs = match (model.uvs.take(), &uvs) {
+            (Some(mut all), Some(uvs)) => {
+                all.extend(indices.iter().map(|&i| Vec2::from_array(uvs[i])));
+                Some(all)
+            }
+            _ => None,
+        };
+
+        if model.base_color.is_none() {
+            let texture = primitive
+                .material()
+                .pbr_metallic_roughness()
+                .base_color_texture();
+            if let Some(info) = texture {
+                let image = &images[info.texture().source().index()];
+                model.base_color = rgba_pixels(image)
+                    .map(|pixels| (image.width as usize, image.height as usize, pixels));
+            }
+        }
+    }
+
+    Ok(model)
+}
+
+/// The transform from the first node that uses the mesh to the scene, including its parents.
+fn mesh_transform(document: &gltf::Document, mesh_index: usize) -> Option<Mat4> {
+    fn find(node: gltf::Node, parent: Mat4, mesh_index: usize) -> Option<Mat4> {
+        let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+        if node.mesh().map(|mesh| mesh.index()) == Some(mesh_index) {
+            return Some(transform);
+        }
+        node.children()
+            .find_map(|child| find(child, transform, mesh_index))
+    }
+
+    document
+        .scenes()
+        .flat_map(|scene| scene.nodes())
+        .find_map(|node| find(node, Mat4::IDENTITY, mesh_index))
+}
+
+/// Converts the image to 8bit RGBA, or returns None if it's in an unsupported format.
+fn rgba_pixels(image: &gltf::image::Data) -> Option<Vec<u8>> {
+    match image.format {
+        Format::R8G8B8A8 => Some(image.pixels.clone()),
+        Format::R8G8B8 => Some(
+            image
+                .pixels
+                .chunks_exact(3)
+                .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+                .collect(),
+        ),
+        format => {
+            log::warn!("Unsupported glTF texture format {:?}", format);
+            None
+        }
+    }
+}
+
+impl GltfModel {
+    /// Creates a mesh of the model, uploading its base color texture if it has one and UVs and
+    /// the texture fits on the device.
+    pub fn create_mesh(&self, gpu: &mut Gpu) -> Mesh {
+        let texture_and_uvs = match (&self.base_color, &self.uvs) {
+            (Some((width, height, pixels)), Some(uvs)) => {
+                match gpu.create_texture(*width, *height, true, WrapMode::Repeat, 1) {
+                    Ok(texture) => {
+                        gpu.write_rgba_texture(texture, pixels);
+                        Some((texture, uvs.as_slice()))
+                    }
+                    Err(e) => {
+                        log::warn!("Skipping the glTF base color texture: {}", e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        Mesh::new(&self.positions, None, texture_and_uvs, gpu)
+    }
+}
@@ -1,13 +1,100 @@
-use crate::math::transform_2d;
+use crate::math::{thick_line_triangles, transform_2d, Aabb};
 use crate::prelude::*;
-use bytemuck;
-use pollster;
 use std::mem::size_of;
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::Arc;
-use wgpu;
 use winit::window::Window;
 
 const WHITE_TEXTURE_ID: usize = 0;
+const MAX_PENDING_TIMESTAMP_READBACKS: usize = 3;
+const INITIAL_UNIFORM_CAPACITY: u32 = 256; // Draws per frame before the uniform buffer grows
+
+/// Optional capabilities of the adapter that the Gpu was created with.
+struct AdapterSupport {
+    timestamps: bool,
+    max_anisotropy: u16, // 1 if anisotropic filtering is unsupported
+}
+
+/// How a texture is sampled outside of its 0 to 1 UV range.
+#[derive(Default, Copy, Clone, PartialEq)]
+pub enum WrapMode {
+    #[default]
+    Clamp,
+    Repeat,
+    MirrorRepeat,
+}
+
+impl WrapMode {
+    fn address_mode(self) -> wgpu::AddressMode {
+        match self {
+            WrapMode::Clamp => wgpu::AddressMode::ClampToEdge,
+            WrapMode::Repeat => wgpu::AddressMode::Repeat,
+            WrapMode::MirrorRepeat => wgpu::AddressMode::MirrorRepeat,
+        }
+    }
+}
+
+/// Why a Gpu couldn't be created for a window.
+#[derive(Debug)]
+pub enum GpuInitError {
+    CreateSurface(wgpu::CreateSurfaceError),
+    NoAdapter,
+    NoDevice(wgpu::RequestDeviceError),
+    SurfaceUnsupported, // The adapter can't present to the window's surface
+}
+
+impl std::fmt::Display for GpuInitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GpuInitError::CreateSurface(e) => write!(f, "Failed to create a surface: {}", e),
+            GpuInitError::NoAdapter => write!(f, "No compatible graphics adapter found"),
+            GpuInitError::NoDevice(e) => write!(f, "Failed to create a device: {}", e),
+            GpuInitError::SurfaceUnsupported => {
+                write!(f, "The graphics adapter can't present to the window")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GpuInitError {}
+
+/// A texture was requested with a side longer than the device allows.
+#[derive(Debug)]
+pub struct TextureTooLarge {
+    pub width: usize,
+    pub height: usize,
+    pub max_size: u32,
+}
+
+impl std::fmt::Display for TextureTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "A {}x{} texture exceeds the maximum size of {}",
+            self.width, self.height, self.max_size
+        )
+    }
+}
+
+impl std::error::Error for TextureTooLarge {}
+
+/// Why create_texture_from_file couldn't create a texture.
+#[derive(Debug)]
+pub enum TextureFileError {
+    Image(image::ImageError), // The file couldn't be read or decoded
+    TooLarge(TextureTooLarge),
+}
+
+impl std::fmt::Display for TextureFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TextureFileError::Image(e) => write!(f, "Failed to load the image: {}", e),
+            TextureFileError::TooLarge(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TextureFileError {}
 
 struct Texture {
     texture: wgpu::Texture,
@@ -15,8 +102,70 @@ struct Texture {
     bindgroup: wgpu::BindGroup,
 }
 
+/// The pixels of a finished frame, in tightly packed 8bit RGBA rows from top to bottom.
+pub struct FramePixels {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+/// Strips the row padding that texture-to-buffer copies require and converts to opaque RGBA.
+/// The window is opaque, so the alpha that blending leaves in the target is discarded.
+fn unpad_frame_rows(
+    padded: &[u8],
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+    bgra: bool,
+) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity((width * height * 4) as usize);
+    for row in padded
+        .chunks(padded_bytes_per_row as usize)
+        .take(height as usize)
+    {
+        for pixel in row[..(width * 4) as usize].chunks(4) {
+            if bgra {
+                rgba.extend_from_slice(&[pixel[2], pixel[1], pixel[0], 0xff]);
+            } else {
+                rgba.extend_from_slice(&[pixel[0], pixel[1], pixel[2], 0xff]);
+            }
+        }
+    }
+    rgba
+}
+
+/// A rectangle of the window in physical pixels, measured from its top left corner.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Viewport {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Viewport {
+    pub fn aspect_ratio(&self) -> f32 {
+        self.width as f32 / self.height as f32
+    }
+
+    /// Maps window pixels to the viewport's normalized coordinates, where y runs from 1 at its
+    /// top to -1 at its bottom and x spans the aspect ratio either side of its middle.
+    fn pixels_to_normalized(&self) -> Mat4 {
+        let origin = Mat4::from_translation(Vec3::new(-(self.x as f32), -(self.y as f32), 0.0));
+        let height = self.height as f32;
+        let pixels_to_normalized = Mat4::from_scale(Vec3::new(2.0 / height, -2.0 / height, 1.0));
+        let translation = Mat4::from_translation(Vec3::new(-self.aspect_ratio(), 1.0, 0.0));
+        translation * pixels_to_normalized * origin
+    }
+}
+
+enum RenderTarget<'a> {
+    Surface(wgpu::Surface<'a>),
+    Offscreen(wgpu::Texture), // Used by headless Gpus, which have no window
+}
+
 struct FrameObjects {
-    surface_texture: wgpu::SurfaceTexture,
+    surface_texture: Option<wgpu::SurfaceTexture>, // None when rendering offscreen
     command_encoder: wgpu::CommandEncoder,
     render_pass: Option<wgpu::RenderPass<'static>>,
 }
@@ -26,6 +175,8 @@ pub struct Mesh {
     positions: wgpu::Buffer,
     vert_colors: wgpu::Buffer,
     uvs: wgpu::Buffer,
+    bounds: Aabb,
+    lines: bool, // Pairs of vertices form lines rather than triples forming triangles
     pub texture: usize, // TODO: this pub is smelly.
 }
 
@@ -41,6 +192,36 @@ impl Mesh {
         mesh
     }
 
+    /// Creates a mesh that draws a line between each pair of positions.
+    pub fn new_lines(positions: &[Vec3], vert_colors: Option<&[Vec4]>, gpu: &Gpu) -> Self {
+        debug_assert_eq!(positions.len() % 2, 0);
+        let mut mesh = Self::new(positions, vert_colors, None, gpu);
+        mesh.lines = true;
+        mesh
+    }
+
+    /// Creates a mesh of antialiased lines, width pixels thick, between each pair of positions.
+    /// The lines are expanded on the CPU through the matrix, so render the mesh with the
+    /// identity matrix and recreate it when the matrix or window size changes.
+    pub fn new_thick_lines(
+        segments: &[(Vec3, Vec3)],
+        width: f32,
+        color: Vec4,
+        matrix: &Mat4,
+        gpu: &Gpu,
+    ) -> Self {
+        // Normalized coordinates span 2 units vertically, and the fringe is a pixel wide.
+        let pixel = 2.0 / gpu.height() as f32;
+        let triangles = thick_line_triangles(segments, matrix, width * pixel * 0.5, pixel);
+
+        let positions: Vec<Vec3> = triangles.iter().map(|(pos, _)| *pos).collect();
+        let colors: Vec<Vec4> = triangles
+            .iter()
+            .map(|(_, alpha)| Vec4::new(color.x, color.y, color.z, color.w * alpha))
+            .collect();
+        Self::new(&positions, Some(&colors), None, gpu)
+    }
+
     pub fn new_2d(
         positions: &[Vec2],
         vert_colors: Option<&[Vec4]>,
@@ -65,10 +246,18 @@ impl Mesh {
             positions,
             vert_colors,
             uvs,
+            bounds: Aabb::new(Vec3::ZERO, Vec3::ZERO),
+            lines: false,
             texture: 0,
         }
     }
 
+    /// The extents of the positions in the mesh's own space, or a zero-size box at the origin if
+    /// the mesh is empty.
+    pub fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+
     fn write(
         &mut self,
         positions: &[Vec3],
@@ -78,6 +267,7 @@ impl Mesh {
     ) {
         debug_assert_eq!(positions.len(), self.vert_count);
         Self::write_vec3_slice_to_buffer(&self.positions, positions, &gpu.queue);
+        self.bounds = Aabb::from_points(positions).unwrap_or(Aabb::new(Vec3::ZERO, Vec3::ZERO));
 
         if let Some(colors) = vert_colors {
             debug_assert_eq!(colors.len(), self.vert_count);
@@ -145,72 +335,291 @@ impl Mesh {
     }
 }
 
-struct Uniform {
+/// One buffer holding every draw's uniforms for a frame, bound with a dynamic offset per draw,
+/// so that there's a single bind group rather than one per draw.
+struct UniformBuffer {
     buffer: wgpu::Buffer,
     bindgroup: wgpu::BindGroup,
+    capacity: u32, // The number of draws the buffer has room for
+    stride: u32,   // The size of each draw's uniforms, padded to the offset alignment
 }
 
-impl Uniform {
-    fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout) -> Self {
+impl UniformBuffer {
+    // The exposure is padded to a Vec4 to match the shader struct's alignment.
+    const SIZE: usize = size_of::<Mat4>() + size_of::<Vec4>() * 3;
+
+    fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, capacity: u32) -> Self {
         debug_assert_eq!(size_of::<Mat4>(), 16 * 4);
         debug_assert_eq!(size_of::<Vec4>(), 4 * 4);
 
-        let buffer = {
-            let desc = wgpu::BufferDescriptor {
-                label: None,
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-                size: (size_of::<Mat4>() + size_of::<Vec4>()) as u64,
-                mapped_at_creation: false,
-            };
-            device.create_buffer(&desc)
-        };
+        let alignment = device.limits().min_uniform_buffer_offset_alignment;
+        let stride = (Self::SIZE as u32).div_ceil(alignment) * alignment;
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            size: stride as u64 * capacity as u64,
+            mapped_at_creation: false,
+        });
 
         let bindgroup = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
-                resource: buffer.as_entire_binding(),
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(Self::SIZE as u64),
+                }),
             }],
             label: None,
         });
 
-        Self { buffer, bindgroup }
+        Self {
+            buffer,
+            bindgroup,
+            capacity,
+            stride,
+        }
     }
 
-    fn as_bytes(&self, matrix: &Mat4, color: &Vec4) -> Vec<u8> {
+    fn bytes(matrix: &Mat4, color: &Vec4, exposure: f32, fog: &Vec4) -> Vec<u8> {
         let matrix_floats = matrix.to_cols_array();
         let matrix_bytes = bytemuck::bytes_of(&matrix_floats);
 
         let color_floats = color.to_array();
         let color_bytes = bytemuck::bytes_of(&color_floats);
 
-        let mut uniform_bytes = Vec::with_capacity(matrix_bytes.len() + color_bytes.len());
+        let exposure_floats = [exposure, 0.0, 0.0, 0.0];
+        let exposure_bytes = bytemuck::bytes_of(&exposure_floats);
+
+        let fog_floats = fog.to_array();
+        let fog_bytes = bytemuck::bytes_of(&fog_floats);
+
+        let mut uniform_bytes = Vec::with_capacity(Self::SIZE);
         uniform_bytes.extend_from_slice(matrix_bytes);
         uniform_bytes.extend_from_slice(color_bytes);
+        uniform_bytes.extend_from_slice(exposure_bytes);
+        uniform_bytes.extend_from_slice(fog_bytes);
         uniform_bytes
     }
 }
 
+/// How a draw's colors combine with what's already in the target.
+#[derive(Default, Copy, Clone, PartialEq, Debug)]
+pub enum BlendMode {
+    /// Mixes by the draw's alpha, for translucency.
+    #[default]
+    Alpha,
+    /// Adds the draw's color scaled by its alpha, for glows.
+    Additive,
+    /// Multiplies the target by the draw's color, for darkening.
+    Multiply,
+    /// Replaces the target, ignoring alpha.
+    Opaque,
+}
+
+impl BlendMode {
+    const ALL: [BlendMode; 4] = [
+        BlendMode::Alpha,
+        BlendMode::Additive,
+        BlendMode::Multiply,
+        BlendMode::Opaque,
+    ];
+
+    fn blend_state(self) -> Option<wgpu::BlendState> {
+        use wgpu::{BlendComponent, BlendFactor, BlendOperation, BlendState};
+        let color = match self {
+            BlendMode::Alpha => return Some(BlendState::ALPHA_BLENDING), // TODO: not premultiplied
+            BlendMode::Additive => BlendComponent {
+                src_factor: BlendFactor::SrcAlpha,
+                dst_factor: BlendFactor::One,
+                operation: BlendOperation::Add,
+            },
+            BlendMode::Multiply => BlendComponent {
+                src_factor: BlendFactor::Dst,
+                dst_factor: BlendFactor::Zero,
+                operation: BlendOperation::Add,
+            },
+            BlendMode::Opaque => return None,
+        };
+        Some(BlendState {
+            color,
+            alpha: BlendComponent::OVER,
+        })
+    }
+}
+
+/// The same depth and color write state for each kind of primitive, with a pipeline per blend
+/// mode indexed by the mode.
+struct PipelineVariants {
+    triangles: Vec<wgpu::RenderPipeline>,
+    lines: Vec<wgpu::RenderPipeline>,
+}
+
 struct Pipelines {
-    no_depth_test: wgpu::RenderPipeline,
-    depth_test: wgpu::RenderPipeline,
+    no_depth_test: PipelineVariants,
+    depth_test: PipelineVariants,
+    depth_prepass: PipelineVariants, // Writes depth only
+    // Tests against depth without writing it, for shading after a prepass and for translucency.
+    depth_test_read_only: PipelineVariants,
+}
+
+/// Measures how long the GPU spends on each frame's render pass with timestamp queries. The
+/// results are read back asynchronously a frame or more later, so reading them never stalls.
+struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    idle_readbacks: Vec<wgpu::Buffer>,
+    pending_readbacks: VecDeque<(wgpu::Buffer, Arc<AtomicU8>)>, // Holds a ReadbackState
+    period: f32,                                                // Nanoseconds per timestamp tick
+    frame_time: Option<f32>,
+}
+
+impl GpuTimer {
+    // The states of a pending readback, set by its map_async callback.
+    const READBACK_PENDING: u8 = 0;
+    const READBACK_MAPPED: u8 = 1;
+    const READBACK_FAILED: u8 = 2;
+
+    const BUFFER_SIZE: u64 = 2 * wgpu::QUERY_SIZE as u64; // Start and end of the render pass
+
+    fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: None,
+            ty: wgpu::QueryType::Timestamp,
+            count: 2,
+        });
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            size: Self::BUFFER_SIZE,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            query_set,
+            resolve_buffer,
+            idle_readbacks: vec![],
+            pending_readbacks: VecDeque::new(),
+            period: queue.get_timestamp_period(),
+            frame_time: None,
+        }
+    }
+
+    fn timestamp_writes(&self) -> wgpu::RenderPassTimestampWrites {
+        wgpu::RenderPassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(0),
+            end_of_pass_write_index: Some(1),
+        }
+    }
+
+    /// Copies this frame's timestamps to a readback buffer. Returns the buffer so that it can be
+    /// mapped after submission, or None if too many earlier readbacks are still in flight.
+    fn resolve(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Option<wgpu::Buffer> {
+        if self.idle_readbacks.is_empty()
+            && self.pending_readbacks.len() >= MAX_PENDING_TIMESTAMP_READBACKS
+        {
+            return None;
+        }
+
+        let readback = self.idle_readbacks.pop().unwrap_or_else(|| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                size: Self::BUFFER_SIZE,
+                mapped_at_creation: false,
+            })
+        });
+
+        encoder.resolve_query_set(&self.query_set, 0..2, &self.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(&self.resolve_buffer, 0, &readback, 0, Self::BUFFER_SIZE);
+        Some(readback)
+    }
+
+    fn map_readback(&mut self, readback: wgpu::Buffer) {
+        let state = Arc::new(AtomicU8::new(Self::READBACK_PENDING));
+        let state_in_callback = state.clone();
+        readback
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                let new_state = match result {
+                    Ok(()) => Self::READBACK_MAPPED,
+                    Err(_) => Self::READBACK_FAILED,
+                };
+                state_in_callback.store(new_state, Ordering::Release);
+            });
+        self.pending_readbacks.push_back((readback, state));
+    }
+
+    /// Reads the timestamps of any readbacks that have finished mapping, oldest first. Readbacks
+    /// that failed to map are dropped so they don't hold up the ones behind them.
+    fn collect(&mut self) {
+        while let Some((_, state)) = self.pending_readbacks.front() {
+            let state = state.load(Ordering::Acquire);
+            if state == Self::READBACK_PENDING {
+                break;
+            }
+            let (readback, _) = self.pending_readbacks.pop_front().unwrap();
+            if state == Self::READBACK_FAILED {
+                log::warn!("Failed to map a GPU timestamp readback");
+                continue;
+            }
+
+            {
+                let bytes = readback.slice(..).get_mapped_range();
+                let timestamps: &[u64] = bytemuck::cast_slice(&bytes);
+                let ticks = timestamps[1].wrapping_sub(timestamps[0]);
+                self.frame_time = Some(ticks as f32 * self.period / 1_000_000_000.0);
+            }
+            readback.unmap();
+            self.idle_readbacks.push(readback);
+        }
+    }
 }
 
 pub struct Gpu<'a> {
-    surface: wgpu::Surface<'a>,
+    target: RenderTarget<'a>,
+    surface_config: wgpu::SurfaceConfiguration, // Also describes the offscreen target
+
     device: wgpu::Device,
     queue: wgpu::Queue,
     pipelines: Pipelines,
     depth_texture_view: wgpu::TextureView,
     uniform_bindgroup_layout: wgpu::BindGroupLayout,
     texture_bindgroup_layout: wgpu::BindGroupLayout,
-    textures: Vec<Texture>,
+    textures: Vec<Option<Texture>>, // None marks a destroyed texture's reusable slot
+    cubemap_bindgroup_layout: wgpu::BindGroupLayout,
+    cubemaps: Vec<Texture>,
+    skybox_pipeline: wgpu::RenderPipeline,
+    billboard_quad: Option<Mesh>, // Only None while render_billboard is drawing it
     frame_objects: Option<FrameObjects>,
-    busy_uniforms: Vec<Uniform>,
-    idle_uniforms: Vec<Uniform>,
+    uniforms: UniformBuffer,
+    uniform_count: u32, // The number of draws whose uniforms are in the buffer this frame
     width: usize,
     height: usize,
+    viewport: Viewport, // The whole window unless set_viewport has been called this frame
     render_count: u32,
+    exposure: f32,
+    fog: Vec4,               // The color in xyz and the density in w
+    timer: Option<GpuTimer>, // None if the adapter doesn't support timestamp queries
+    monochrome_scratch: Vec<u8>,
+    should_read_frame: bool,
+    read_frame: Option<FramePixels>,
+    pipeline_variants: fn(&Pipelines) -> &PipelineVariants,
+    blend_mode: BlendMode,
+    // Whether the bound pipeline is for lines and its blend mode, or None if none is bound.
+    bound_pipeline: Option<(bool, BlendMode)>,
+    bound_mesh: Option<wgpu::Id<wgpu::Buffer>>, // The positions buffer of the bound mesh
+    bound_texture: Option<usize>,
+    max_anisotropy: u16,
+    command_count: u32, // Render pass commands recorded this frame
+    previous_command_count: u32,
 }
 
 impl<'a> Gpu<'a> {
@@ -222,16 +631,27 @@ impl<'a> Gpu<'a> {
         self.height
     }
 
+    /// The aspect ratio of the current viewport, which render_mesh corrects for.
     pub fn aspect_ratio(&self) -> f32 {
-        self.width() as f32 / self.height() as f32
+        self.viewport.aspect_ratio()
+    }
+
+    pub fn viewport(&self) -> Viewport {
+        self.viewport
     }
 
+    fn window_viewport(&self) -> Viewport {
+        Viewport {
+            x: 0,
+            y: 0,
+            width: self.width as u32,
+            height: self.height as u32,
+        }
+    }
+
+    /// Maps window pixels to the whole window's normalized coordinates, whatever the viewport.
     pub fn window_to_normalized_transform(&self) -> Mat4 {
-        let width = self.width() as f32;
-        let height = self.height() as f32;
-        let pixels_to_normalized = Mat4::from_scale(Vec3::new(2.0 / height, -2.0 / height, 1.0));
-        let translation = Mat4::from_translation(Vec3::new(-self.aspect_ratio(), 1.0, 0.0));
-        translation * pixels_to_normalized
+        self.window_viewport().pixels_to_normalized()
     }
 
     pub fn window_to_normalized(&self, window_pos: &Vec2) -> Vec2 {
@@ -245,63 +665,219 @@ impl<'a> Gpu<'a> {
         )
     }
 
-    pub fn new(window: &Arc<Window>) -> Gpu<'a> {
-        let (surface, adapter) = {
-            let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-                backends: wgpu::Backends::all(),
-                ..Default::default()
-            });
-            let surface = instance.create_surface(window.clone()).unwrap();
-            let adapter =
-                pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
-                    power_preference: wgpu::PowerPreference::HighPerformance,
-                    compatible_surface: Some(&surface),
-                    force_fallback_adapter: false,
-                }))
-                .unwrap();
-            let info = adapter.get_info();
-            println!(
-                "backend: {}\nDriver: {}\nInfo: {}",
-                info.backend, info.driver, info.driver_info
-            );
-            let limits = adapter.limits();
-            println!("2D texture limit: {}", limits.max_texture_dimension_2d);
-            (surface, adapter)
+    /// Maps normalized coordinates of the whole window, like the mouse position, to the
+    /// viewport's normalized coordinates.
+    pub fn window_to_viewport(&self, normalized_pos: &Vec2, viewport: &Viewport) -> Vec2 {
+        let window_to_pixels = self.window_to_normalized_transform().inverse();
+        transform_2d(
+            normalized_pos,
+            &(viewport.pixels_to_normalized() * window_to_pixels),
+        )
+    }
+
+    pub fn viewport_to_window(&self, normalized_pos: &Vec2, viewport: &Viewport) -> Vec2 {
+        let pixels_to_window = self.window_to_normalized_transform();
+        transform_2d(
+            normalized_pos,
+            &(pixels_to_window * viewport.pixels_to_normalized().inverse()),
+        )
+    }
+
+    /// Async because the browser can't block on adapter and device requests. Native callers can
+    /// use pollster::block_on.
+    pub async fn new(
+        window: &Arc<Window>,
+        present_mode: wgpu::PresentMode,
+    ) -> Result<Gpu<'a>, GpuInitError> {
+        let instance = Self::create_instance();
+        let surface = instance
+            .create_surface(window.clone())
+            .map_err(GpuInitError::CreateSurface)?;
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: Some(&surface),
+                force_fallback_adapter: false,
+            })
+            .await
+            .ok_or(GpuInitError::NoAdapter)?;
+
+        let size = window.inner_size(); // Size in physical pixels
+        let (device, queue, support) = Self::request_device(&adapter, size.width, size.height)
+            .await
+            .map_err(GpuInitError::NoDevice)?;
+
+        let mut surface_config = surface
+            .get_default_config(&adapter, size.width, size.height)
+            .ok_or(GpuInitError::SurfaceUnsupported)?;
+        // TODO: try surface_config.desired_maximum_frame_latency = 1;
+
+        let capabilities = surface.get_capabilities(&adapter);
+
+        // Frames can only be read back if the surface allows copying from it.
+        if capabilities.usages.contains(wgpu::TextureUsages::COPY_SRC) {
+            surface_config.usage |= wgpu::TextureUsages::COPY_SRC;
+        }
+
+        // The Auto modes always succeed, but specific modes depend on the platform.
+        let supported_modes = capabilities.present_modes;
+        surface_config.present_mode = match present_mode {
+            wgpu::PresentMode::AutoVsync | wgpu::PresentMode::AutoNoVsync => present_mode,
+            _ if supported_modes.contains(&present_mode) => present_mode,
+            _ => {
+                log::warn!("Present mode {:?} unsupported, using Fifo", present_mode);
+                wgpu::PresentMode::Fifo
+            }
+        };
+        surface.configure(&device, &surface_config);
+
+        Ok(Self::from_device(
+            RenderTarget::Surface(surface),
+            surface_config,
+            device,
+            queue,
+            support,
+        ))
+    }
+
+    /// Creates a Gpu that renders into an offscreen texture instead of a window, so rendering
+    /// can be tested without a display. Returns None if there's no suitable adapter or device.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new_headless(width: u32, height: u32) -> Option<Gpu<'a>> {
+        let instance = Self::create_instance();
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        }))?;
+
+        let (device, queue, support) =
+            pollster::block_on(Self::request_device(&adapter, width, height)).ok()?;
+
+        // There's no surface to configure, but the configuration still describes the target.
+        let surface_config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+            desired_maximum_frame_latency: 2,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
         };
+        let texture = Self::create_offscreen_texture(&device, &surface_config);
+
+        Some(Self::from_device(
+            RenderTarget::Offscreen(texture),
+            surface_config,
+            device,
+            queue,
+            support,
+        ))
+    }
+
+    fn create_instance() -> wgpu::Instance {
+        wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            ..Default::default()
+        })
+    }
+
+    /// Returns the device, its queue and whether timestamp queries were enabled.
+    async fn request_device(
+        adapter: &wgpu::Adapter,
+        width: u32,
+        height: u32,
+    ) -> Result<(wgpu::Device, wgpu::Queue, AdapterSupport), wgpu::RequestDeviceError> {
+        let info = adapter.get_info();
+        log::info!(
+            "backend: {}\nDriver: {}\nInfo: {}",
+            info.backend,
+            info.driver,
+            info.driver_info
+        );
+        log::debug!(
+            "2D texture limit: {}",
+            adapter.limits().max_texture_dimension_2d
+        );
 
         let mut limits = wgpu::Limits::downlevel_defaults();
         limits.max_texture_dimension_2d = 2048;
 
-        // Increase the texture size limit if it's smaller than the window.
-        while limits.max_texture_dimension_2d < window.inner_size().width
-            || limits.max_texture_dimension_2d < window.inner_size().height
-        {
+        // Increase the texture size limit if it's smaller than the render target.
+        while limits.max_texture_dimension_2d < width || limits.max_texture_dimension_2d < height {
             limits.max_texture_dimension_2d *= 2;
         }
-        println!(
+        log::debug!(
             "Adjusted 2D texture limit: {}",
             limits.max_texture_dimension_2d
         );
 
-        let (device, queue) = pollster::block_on(adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                required_features: wgpu::Features::empty(),
-                required_limits: limits,
-                label: None,
-                memory_hints: wgpu::MemoryHints::Performance,
-            },
-            None,
-        ))
-        .unwrap();
+        let supports_timestamps = adapter.features().contains(wgpu::Features::TIMESTAMP_QUERY);
+        if !supports_timestamps {
+            log::info!("Timestamp queries unsupported, GPU frame times won't be available");
+        }
 
-        let size = window.inner_size(); // Size in physical pixels
-        let surface_config = surface
-            .get_default_config(&adapter, size.width, size.height)
-            .unwrap();
-        // TODO: try surface_config.desired_maximum_frame_latency = 1;
-        debug_assert_eq!(surface_config.present_mode, wgpu::PresentMode::Fifo);
-        surface.configure(&device, &surface_config);
+        // wgpu doesn't report the maximum, but 16 is the most that any backend allows.
+        let downlevel_flags = adapter.get_downlevel_capabilities().flags;
+        let max_anisotropy =
+            if downlevel_flags.contains(wgpu::DownlevelFlags::ANISOTROPIC_FILTERING) {
+                16
+            } else {
+                log::info!("Anisotropic filtering unsupported");
+                1
+            };
+
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    required_features: if supports_timestamps {
+                        wgpu::Features::TIMESTAMP_QUERY
+                    } else {
+                        wgpu::Features::empty()
+                    },
+                    required_limits: limits,
+                    label: None,
+                    memory_hints: wgpu::MemoryHints::Performance,
+                },
+                None,
+            )
+            .await?;
+
+        let support = AdapterSupport {
+            timestamps: supports_timestamps,
+            max_anisotropy,
+        };
+        Ok((device, queue, support))
+    }
+
+    fn create_offscreen_texture(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: config.width,
+                height: config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: config.format,
+            usage: config.usage,
+            label: None,
+            view_formats: &[],
+        })
+    }
 
+    fn from_device(
+        target: RenderTarget<'a>,
+        surface_config: wgpu::SurfaceConfiguration,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        support: AdapterSupport,
+    ) -> Gpu<'a> {
         let uniform_bindgroup_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[wgpu::BindGroupLayoutEntry {
@@ -309,8 +885,8 @@ impl<'a> Gpu<'a> {
                     visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(UniformBuffer::SIZE as u64),
                     },
                     count: None,
                 }],
@@ -342,77 +918,209 @@ impl<'a> Gpu<'a> {
                 label: None,
             });
 
+        let cubemap_bindgroup_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::Cube,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                label: None,
+            });
+
+        let skybox_pipeline = Self::create_skybox_pipeline(
+            &device,
+            &surface_config,
+            &[&uniform_bindgroup_layout, &cubemap_bindgroup_layout],
+        );
+
         let pipelines = {
-            let depth_test = Self::create_pipeline(
-                &device,
-                &surface_config,
-                &[&uniform_bindgroup_layout, &texture_bindgroup_layout],
-                true,
-            );
-            let no_depth_test = Self::create_pipeline(
-                &device,
-                &surface_config,
-                &[&uniform_bindgroup_layout, &texture_bindgroup_layout],
-                false,
-            );
+            let create = |depth_compare, depth_write, color_writes| {
+                let create_for_topology = |topology| {
+                    BlendMode::ALL
+                        .iter()
+                        .map(|blend_mode| {
+                            Self::create_pipeline(
+                                &device,
+                                &surface_config,
+                                &[&uniform_bindgroup_layout, &texture_bindgroup_layout],
+                                topology,
+                                depth_compare,
+                                depth_write,
+                                color_writes,
+                                blend_mode.blend_state(),
+                            )
+                        })
+                        .collect()
+                };
+                PipelineVariants {
+                    triangles: create_for_topology(wgpu::PrimitiveTopology::TriangleList),
+                    lines: create_for_topology(wgpu::PrimitiveTopology::LineList),
+                }
+            };
+            use wgpu::{ColorWrites, CompareFunction};
             Pipelines {
-                depth_test,
-                no_depth_test,
+                depth_test: create(CompareFunction::Less, true, ColorWrites::ALL),
+                no_depth_test: create(CompareFunction::Always, true, ColorWrites::ALL),
+                depth_prepass: create(CompareFunction::Less, true, ColorWrites::empty()),
+                depth_test_read_only: create(CompareFunction::LessEqual, false, ColorWrites::ALL),
             }
         };
 
-        let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
-            size: wgpu::Extent3d {
-                width: size.width,
-                height: size.height,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Depth32Float,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
-            label: Some("depth texture"),
-            view_formats: &[],
-        });
+        let depth_texture_view =
+            Self::create_depth_texture_view(&device, surface_config.width, surface_config.height);
+
+        let timer = support.timestamps.then(|| GpuTimer::new(&device, &queue));
+        let uniforms =
+            UniformBuffer::new(&device, &uniform_bindgroup_layout, INITIAL_UNIFORM_CAPACITY);
 
         let mut gpu = Self {
-            width: window.inner_size().width as usize,
-            height: window.inner_size().height as usize,
-            surface,
+            width: surface_config.width as usize,
+            height: surface_config.height as usize,
+            viewport: Viewport {
+                x: 0,
+                y: 0,
+                width: surface_config.width,
+                height: surface_config.height,
+            },
+            target,
+            surface_config,
             device,
             queue,
             pipelines,
-            depth_texture_view: depth_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            depth_texture_view,
             uniform_bindgroup_layout,
             texture_bindgroup_layout,
             textures: vec![],
+            cubemap_bindgroup_layout,
+            cubemaps: vec![],
+            skybox_pipeline,
+            billboard_quad: None,
             frame_objects: None,
-            busy_uniforms: vec![],
-            idle_uniforms: vec![],
+            uniforms,
+            uniform_count: 0,
             render_count: 0,
+            exposure: 1.0,
+            fog: Vec4::ZERO,
+            timer,
+            monochrome_scratch: vec![],
+            should_read_frame: false,
+            read_frame: None,
+            pipeline_variants: |p| &p.no_depth_test,
+            blend_mode: BlendMode::Alpha,
+            bound_pipeline: None,
+            bound_mesh: None,
+            bound_texture: None,
+            max_anisotropy: support.max_anisotropy,
+            command_count: 0,
+            previous_command_count: 0,
         };
 
         // The white texture is used when the user doesn't want texturing; the vertex
         // colors get multiplied with white (255u8), allowing the texturing pipeline to
         // handle non-textured meshes.
-        let white_texture = gpu.create_texture(1, 1, false);
+        let white_texture = gpu
+            .create_texture(1, 1, false, WrapMode::Clamp, 1)
+            .expect("1x1 textures are always supported");
         gpu.write_rgba_texture(white_texture, &[255u8; 4]);
         debug_assert_eq!(white_texture, WHITE_TEXTURE_ID);
 
+        // A unit quad centered on the origin, with v running down the texture.
+        let corners = [
+            Vec2::new(-0.5, -0.5),
+            Vec2::new(0.5, -0.5),
+            Vec2::new(0.5, 0.5),
+            Vec2::new(-0.5, -0.5),
+            Vec2::new(0.5, 0.5),
+            Vec2::new(-0.5, 0.5),
+        ];
+        let uvs = corners.map(|corner| Vec2::new(corner.x + 0.5, 0.5 - corner.y));
+        gpu.billboard_quad = Some(Mesh::new_2d(
+            &corners,
+            None,
+            Some((WHITE_TEXTURE_ID, &uvs)),
+            &gpu,
+        ));
+
         gpu
     }
 
-    fn create_pipeline(
+    fn create_depth_texture_view(
         device: &wgpu::Device,
-        config: &wgpu::SurfaceConfiguration,
-        bind_group_layouts: &[&wgpu::BindGroupLayout],
-        depth_test: bool,
-    ) -> wgpu::RenderPipeline {
-        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/default.wgsl"));
-        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            label: None,
-            bind_group_layouts,
+        width: u32,
+        height: u32,
+    ) -> wgpu::TextureView {
+        let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Depth32Float,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            label: Some("depth texture"),
+            view_formats: &[],
+        });
+        depth_texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    /// Reconfigures the surface and depth buffer to match a new window size in physical pixels.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        // A minimized window reports a zero size, which the surface can't be configured with.
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let max_size = self.device.limits().max_texture_dimension_2d;
+        let width = width.min(max_size);
+        let height = height.min(max_size);
+
+        self.surface_config.width = width;
+        self.surface_config.height = height;
+        match &mut self.target {
+            RenderTarget::Surface(surface) => surface.configure(&self.device, &self.surface_config),
+            RenderTarget::Offscreen(texture) => {
+                *texture = Self::create_offscreen_texture(&self.device, &self.surface_config);
+            }
+        }
+
+        self.depth_texture_view = Self::create_depth_texture_view(&self.device, width, height);
+        self.width = width as usize;
+        self.height = height as usize;
+        self.viewport = self.window_viewport();
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_pipeline(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        bind_group_layouts: &[&wgpu::BindGroupLayout],
+        topology: wgpu::PrimitiveTopology,
+        depth_compare: wgpu::CompareFunction,
+        depth_write: bool,
+        color_writes: wgpu::ColorWrites,
+        blend: Option<wgpu::BlendState>,
+    ) -> wgpu::RenderPipeline {
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/default.wgsl"));
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts,
             push_constant_ranges: &[],
         });
         let vertpos_layout = wgpu::VertexBufferLayout {
@@ -456,13 +1164,13 @@ impl<'a> Gpu<'a> {
                 entry_point: "fs_main",
                 targets: &[Some(wgpu::ColorTargetState {
                     format: config.format,
-                    blend: Some(wgpu::BlendState::ALPHA_BLENDING), // TODO: not premultiplied
-                    write_mask: wgpu::ColorWrites::ALL,
+                    blend,
+                    write_mask: color_writes,
                 })],
                 compilation_options: Default::default(),
             }),
             primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
+                topology,
                 strip_index_format: None,
                 front_face: wgpu::FrontFace::Ccw,
                 cull_mode: None,
@@ -472,12 +1180,8 @@ impl<'a> Gpu<'a> {
             },
             depth_stencil: Some(wgpu::DepthStencilState {
                 format: wgpu::TextureFormat::Depth32Float,
-                depth_write_enabled: true,
-                depth_compare: if depth_test {
-                    wgpu::CompareFunction::Less
-                } else {
-                    wgpu::CompareFunction::Always
-                },
+                depth_write_enabled: depth_write,
+                depth_compare,
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
             }),
@@ -491,7 +1195,72 @@ impl<'a> Gpu<'a> {
         })
     }
 
-    pub fn create_texture(&mut self, width: usize, height: usize, linear_filtering: bool) -> usize {
+    /// The sky is a triangle covering the target with no vertex buffers, and it neither tests
+    /// nor writes depth so that everything drawn after it is in front.
+    fn create_skybox_pipeline(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        bind_group_layouts: &[&wgpu::BindGroupLayout],
+    ) -> wgpu::RenderPipeline {
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/skybox.wgsl"));
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("skybox pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+                compilation_options: Default::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: config.format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: Default::default(),
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: wgpu::TextureFormat::Depth32Float,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        })
+    }
+
+    /// Anisotropy is the number of samples for textures seen at a grazing angle, where 1 turns
+    /// anisotropic filtering off. It's clamped to what the adapter supports, and anything above
+    /// 1 forces linear filtering. Returns an error if either side exceeds max_texture_size.
+    pub fn create_texture(
+        &mut self,
+        width: usize,
+        height: usize,
+        linear_filtering: bool,
+        wrap: WrapMode,
+        anisotropy: u16,
+    ) -> Result<usize, TextureTooLarge> {
+        let max_size = self.max_texture_size();
+        if width > max_size as usize || height > max_size as usize {
+            return Err(TextureTooLarge {
+                width,
+                height,
+                max_size,
+            });
+        }
+
         let size = wgpu::Extent3d {
             width: width as u32,
             height: height as u32,
@@ -503,11 +1272,20 @@ impl<'a> Gpu<'a> {
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format: wgpu::TextureFormat::Rgba8Unorm,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::COPY_SRC,
             label: Some("default gb texture"),
             view_formats: &[],
         });
-        let filter = if linear_filtering {
+        // wgpu only accepts anisotropy with linear filtering throughout.
+        let anisotropy = anisotropy.clamp(1, self.max_anisotropy);
+        let filter = if linear_filtering || anisotropy > 1 {
+            wgpu::FilterMode::Linear
+        } else {
+            wgpu::FilterMode::Nearest
+        };
+        let mipmap_filter = if anisotropy > 1 {
             wgpu::FilterMode::Linear
         } else {
             wgpu::FilterMode::Nearest
@@ -515,12 +1293,13 @@ impl<'a> Gpu<'a> {
         let bindgroup = {
             let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
             let sampler = self.device.create_sampler(&wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_u: wrap.address_mode(),
+                address_mode_v: wrap.address_mode(),
                 address_mode_w: wgpu::AddressMode::ClampToEdge,
                 mag_filter: filter,
                 min_filter: wgpu::FilterMode::Linear,
-                mipmap_filter: wgpu::FilterMode::Nearest,
+                mipmap_filter,
+                anisotropy_clamp: anisotropy,
                 ..Default::default()
             });
             self.device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -539,83 +1318,423 @@ impl<'a> Gpu<'a> {
             })
         };
 
-        self.textures.push(Texture {
+        let texture = Some(Texture {
             texture,
             size,
             bindgroup,
         });
-        self.textures.len() - 1
+
+        match self.textures.iter().position(Option::is_none) {
+            Some(id) => {
+                self.textures[id] = texture;
+                Ok(id)
+            }
+            None => {
+                self.textures.push(texture);
+                Ok(self.textures.len() - 1)
+            }
+        }
     }
 
-    pub fn write_monochrome_texture(&self, texture_id: usize, pixels: &[u8]) {
-        let texture = &self.textures[texture_id];
+    /// The longest side, in texels, of a texture that the device can create.
+    pub fn max_texture_size(&self) -> u32 {
+        self.device.limits().max_texture_dimension_2d
+    }
+
+    /// Decodes a PNG or JPEG file into a new texture of the image's size, converting other pixel
+    /// formats to 8bit RGBA. Returns an error if the file can't be read or decoded, or if the
+    /// image is larger than the device allows.
+    pub fn create_texture_from_file(
+        &mut self,
+        path: &str,
+        linear_filtering: bool,
+    ) -> Result<usize, TextureFileError> {
+        let image = image::open(path)
+            .map_err(TextureFileError::Image)?
+            .to_rgba8();
+        let (width, height) = image.dimensions();
+
+        let texture_id = self
+            .create_texture(
+                width as usize,
+                height as usize,
+                linear_filtering,
+                WrapMode::default(),
+                1,
+            )
+            .map_err(TextureFileError::TooLarge)?;
+        self.write_rgba_texture(texture_id, image.as_raw());
+        Ok(texture_id)
+    }
+
+    /// Creates a cubemap for render_skybox from six square faces of 8bit RGBA pixels, in the
+    /// order +X, -X, +Y, -Y, +Z, -Z. Its ids are separate from create_texture's.
+    pub fn create_cubemap(
+        &mut self,
+        faces: [&[u8]; 6],
+        size: usize,
+    ) -> Result<usize, TextureTooLarge> {
+        let max_size = self.max_texture_size();
+        if size > max_size as usize {
+            return Err(TextureTooLarge {
+                width: size,
+                height: size,
+                max_size,
+            });
+        }
+
+        let size = wgpu::Extent3d {
+            width: size as u32,
+            height: size as u32,
+            depth_or_array_layers: 6,
+        };
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            label: Some("cubemap"),
+            view_formats: &[],
+        });
+
+        for (layer, pixel_bytes) in faces.iter().enumerate() {
+            debug_assert_eq!(
+                pixel_bytes.len(),
+                (size.width * size.height * 4) as usize,
+                "expected 8bit RGBA pixel data for each whole face"
+            );
+            self.queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                pixel_bytes,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(size.width * 4),
+                    rows_per_image: Some(size.height),
+                },
+                wgpu::Extent3d {
+                    depth_or_array_layers: 1,
+                    ..size
+                },
+            );
+        }
+
+        let bindgroup = {
+            let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::Cube),
+                ..Default::default()
+            });
+            let sampler = self.device.create_sampler(&wgpu::SamplerDescriptor {
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            });
+            self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.cubemap_bindgroup_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&texture_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&sampler),
+                    },
+                ],
+                label: Some("cubemap bind group"),
+            })
+        };
+
+        self.cubemaps.push(Texture {
+            texture,
+            size,
+            bindgroup,
+        });
+        Ok(self.cubemaps.len() - 1)
+    }
+
+    fn texture(&self, texture_id: usize) -> &Texture {
+        self.textures[texture_id]
+            .as_ref()
+            .expect("texture has been destroyed")
+    }
+
+    /// Frees the texture and allows its id to be reused. Draws that have already been recorded
+    /// with it this frame are unaffected.
+    pub fn destroy_texture(&mut self, texture_id: usize) {
+        debug_assert_ne!(texture_id, WHITE_TEXTURE_ID);
+        debug_assert!(self.textures[texture_id].is_some());
+        self.textures[texture_id] = None;
+        if self.bound_texture == Some(texture_id) {
+            self.bound_texture = None; // The slot might be reused by a new texture
+        }
+    }
+
+    /// Expands single-channel pixels to opaque gray RGBA. The expansion reuses a scratch buffer,
+    /// so textures updated every frame don't allocate every frame.
+    pub fn write_monochrome_texture(&mut self, texture_id: usize, pixels: &[u8]) {
+        let texture = self.texture(texture_id);
         debug_assert_eq!(
             pixels.len(),
             (texture.size.width * texture.size.height) as usize,
             "expected 8bit single-channel pixel data"
         );
 
-        let mut rgba_pixel_bytes = Vec::with_capacity(pixels.len() * 4);
+        let mut rgba_pixel_bytes = std::mem::take(&mut self.monochrome_scratch);
+        rgba_pixel_bytes.clear();
         for pixel in pixels {
-            rgba_pixel_bytes.push(*pixel);
-            rgba_pixel_bytes.push(*pixel);
-            rgba_pixel_bytes.push(*pixel);
-            rgba_pixel_bytes.push(0xff);
+            rgba_pixel_bytes.extend_from_slice(&[*pixel, *pixel, *pixel, 0xff]);
         }
 
         self.write_rgba_texture(texture_id, &rgba_pixel_bytes);
+        self.monochrome_scratch = rgba_pixel_bytes;
     }
 
     pub fn write_rgba_texture(&self, texture_id: usize, pixel_bytes: &[u8]) {
-        let texture = &self.textures[texture_id];
+        let size = self.texture(texture_id).size;
         debug_assert_eq!(
             pixel_bytes.len(),
-            (texture.size.width * texture.size.height * 4) as usize,
+            (size.width * size.height * 4) as usize,
             "expected entire 8bit RGBA pixel data"
         );
+        self.write_rgba_subregion(texture_id, 0, 0, size.width, size.height, pixel_bytes);
+    }
+
+    /// Writes 8bit RGBA pixel data to a rectangle within the texture.
+    pub fn write_rgba_subregion(
+        &self,
+        texture_id: usize,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        pixel_bytes: &[u8],
+    ) {
+        let texture = self.texture(texture_id);
+        debug_assert!(x + width <= texture.size.width && y + height <= texture.size.height);
+        debug_assert_eq!(
+            pixel_bytes.len(),
+            (width * height * 4) as usize,
+            "expected 8bit RGBA pixel data for the whole region"
+        );
         self.queue.write_texture(
             wgpu::ImageCopyTexture {
                 texture: &texture.texture,
                 mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
+                origin: wgpu::Origin3d { x, y, z: 0 },
                 aspect: wgpu::TextureAspect::All,
             },
             pixel_bytes,
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: Some(texture.size.width * 4),
-                rows_per_image: Some(texture.size.height),
+                bytes_per_row: Some(width * 4),
+                rows_per_image: Some(height),
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
             },
-            texture.size,
         );
     }
 
+    /// Copies the texture back to the CPU as tightly packed 8bit RGBA rows, waiting for the GPU.
+    /// The browser maps buffers asynchronously, so there this returns no pixels.
+    pub fn read_texture(&mut self, texture_id: usize) -> Vec<u8> {
+        let texture = &self.texture(texture_id).texture;
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let (buffer, padded_bytes_per_row) =
+            Self::copy_frame_to_buffer(&self.device, &mut encoder, texture);
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                let _ = sender.send(result);
+            });
+        self.device.poll(wgpu::Maintain::Wait);
+
+        let Ok(Ok(())) = receiver.try_recv() else {
+            log::warn!("Failed to read texture {} back", texture_id);
+            return vec![];
+        };
+        let padded = buffer.slice(..).get_mapped_range();
+        let row_bytes = (texture.width() * 4) as usize;
+        padded
+            .chunks(padded_bytes_per_row as usize)
+            .take(texture.height() as usize)
+            .flat_map(|row| &row[..row_bytes])
+            .copied()
+            .collect()
+    }
+
+    /// The pipeline itself is bound by render_mesh, once it knows the mesh's primitive type.
+    fn set_pipeline(&mut self, variants: fn(&Pipelines) -> &PipelineVariants) {
+        self.pipeline_variants = variants;
+        self.bound_pipeline = None;
+    }
+
+    /// Sets how subsequent meshes blend with the target. Each frame starts with alpha blending.
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.blend_mode = blend_mode;
+    }
+
     pub fn depth_test(&mut self, should_test: bool) {
+        if should_test {
+            self.set_pipeline(|p| &p.depth_test);
+        } else {
+            self.set_pipeline(|p| &p.no_depth_test);
+        }
+    }
+
+    /// Subsequent meshes only write depth. Render the opaque meshes like this first, then call
+    /// end_depth_prepass and render them again, so each pixel is only shaded once.
+    pub fn begin_depth_prepass(&mut self) {
+        self.set_pipeline(|p| &p.depth_prepass);
+    }
+
+    /// Subsequent meshes are only shaded where they match the depth from the prepass.
+    pub fn end_depth_prepass(&mut self) {
+        self.set_pipeline(|p| &p.depth_test_read_only);
+    }
+
+    /// Subsequent meshes are hidden by what's already been drawn but don't hide anything
+    /// themselves, which suits translucent meshes drawn back to front after opaque ones.
+    pub fn depth_test_read_only(&mut self) {
+        self.set_pipeline(|p| &p.depth_test_read_only);
+    }
+
+    /// Restricts rendering to a rectangle in physical pixels, clamped to the window.
+    pub fn set_scissor(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        let x = x.min(self.width as u32);
+        let y = y.min(self.height as u32);
+        let width = width.min(self.width as u32 - x);
+        let height = height.min(self.height as u32 - y);
+
         self.frame_objects
             .as_mut()
             .unwrap()
             .render_pass
             .as_mut()
             .unwrap()
-            .set_pipeline(if should_test {
-                &self.pipelines.depth_test
-            } else {
-                &self.pipelines.no_depth_test
-            });
+            .set_scissor_rect(x, y, width, height);
     }
 
-    pub fn begin_frame(&mut self) {
-        let surface_texture = self.surface.get_current_texture().unwrap();
+    pub fn reset_scissor(&mut self) {
+        self.set_scissor(0, 0, self.width as u32, self.height as u32);
+    }
+
+    /// Restricts subsequent rendering to a rectangle in physical pixels, clamped to the window,
+    /// and scissors to it. Normalized coordinates and the aspect ratio correction then span the
+    /// viewport rather than the window, so each viewport can show its own view of a scene. Each
+    /// frame starts with the whole window.
+    pub fn set_viewport(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        let x = x.min(self.width as u32 - 1);
+        let y = y.min(self.height as u32 - 1);
+        let width = width.clamp(1, self.width as u32 - x);
+        let height = height.clamp(1, self.height as u32 - y);
+        self.viewport = Viewport {
+            x,
+            y,
+            width,
+            height,
+        };
+
+        self.frame_objects
+            .as_mut()
+            .unwrap()
+            .render_pass
+            .as_mut()
+            .unwrap()
+            .set_viewport(x as f32, y as f32, width as f32, height as f32, 0.0, 1.0);
+        self.set_scissor(x, y, width, height);
+    }
+
+    pub fn reset_viewport(&mut self) {
+        self.set_viewport(0, 0, self.width as u32, self.height as u32);
+    }
+
+    /// The time in seconds that the GPU spent rendering a recent frame, if the adapter supports
+    /// timestamp queries. It lags a frame or two behind.
+    pub fn frame_time(&self) -> Option<f32> {
+        self.timer.as_ref().and_then(|timer| timer.frame_time)
+    }
+
+    /// Scales the color of subsequent meshes before it's encoded to sRGB. 1.0 leaves colors
+    /// unchanged.
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure;
+    }
+
+    /// Fades subsequent meshes toward the sRGB color with depth, reaching about 63% fog at a
+    /// depth of 1/density. Depth is measured along the view direction before the perspective
+    /// divide, so it's in the scene's units with a perspective projection and runs from 0 to 1
+    /// across the clip volume otherwise. A density of 0 turns fog off.
+    pub fn set_fog(&mut self, color: Vec3, density: f32) {
+        self.fog = color.extend(density);
+    }
+
+    /// Gets the surface's next texture. An outdated or lost surface is reconfigured and retried
+    /// once, and None means the frame should be skipped.
+    fn acquire_surface_texture(
+        surface: &wgpu::Surface,
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> Result<Option<wgpu::SurfaceTexture>, wgpu::SurfaceError> {
+        match surface.get_current_texture() {
+            Ok(surface_texture) => Ok(Some(surface_texture)),
+            Err(wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost) => {
+                surface.configure(device, config);
+                match surface.get_current_texture() {
+                    Ok(surface_texture) => Ok(Some(surface_texture)),
+                    Err(wgpu::SurfaceError::OutOfMemory) => Err(wgpu::SurfaceError::OutOfMemory),
+                    Err(_) => Ok(None),
+                }
+            }
+            Err(wgpu::SurfaceError::Timeout) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Starts recording a frame. Returns Ok(false) if the surface couldn't provide a texture this
+    /// time, in which case skip rendering and finish_frame, and an error if it never will.
+    pub fn begin_frame(&mut self) -> Result<bool, wgpu::SurfaceError> {
+        let (surface_texture, view) = match &self.target {
+            RenderTarget::Surface(surface) => {
+                let Some(surface_texture) =
+                    Self::acquire_surface_texture(surface, &self.device, &self.surface_config)?
+                else {
+                    return Ok(false);
+                };
+                let view = surface_texture
+                    .texture
+                    .create_view(&wgpu::TextureViewDescriptor::default());
+                (Some(surface_texture), view)
+            }
+            RenderTarget::Offscreen(texture) => {
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                (None, view)
+            }
+        };
 
         let mut command_encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
 
-        let view = surface_texture
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-
-        let mut render_pass = command_encoder
+        let render_pass = command_encoder
             .begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: None,
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
@@ -635,11 +1754,12 @@ impl<'a> Gpu<'a> {
                     stencil_ops: None,
                 }),
                 occlusion_query_set: None,
-                timestamp_writes: None,
+                timestamp_writes: self.timer.as_ref().map(|timer| timer.timestamp_writes()),
             })
             .forget_lifetime();
 
-        render_pass.set_pipeline(&self.pipelines.no_depth_test);
+        self.set_pipeline(|p| &p.no_depth_test);
+        self.blend_mode = BlendMode::Alpha;
 
         self.frame_objects = Some(FrameObjects {
             surface_texture,
@@ -648,38 +1768,256 @@ impl<'a> Gpu<'a> {
         });
 
         self.render_count = 0;
+        self.uniform_count = 0;
+        self.viewport = self.window_viewport();
+        self.bound_mesh = None;
+        self.bound_texture = None;
+        self.previous_command_count = self.command_count;
+        self.command_count = 0;
+        Ok(true)
+    }
+
+    /// The number of render pass commands recorded in the previous frame, for profiling.
+    pub fn command_count(&self) -> u32 {
+        self.previous_command_count
+    }
+
+    /// Reads back the pixels of the current frame once it's finished, for take_read_frame.
+    pub fn read_frame(&mut self) {
+        if !self
+            .surface_config
+            .usage
+            .contains(wgpu::TextureUsages::COPY_SRC)
+        {
+            log::warn!("This surface doesn't support reading frames back");
+            return;
+        }
+        if !matches!(
+            self.surface_config.format,
+            wgpu::TextureFormat::Rgba8Unorm
+                | wgpu::TextureFormat::Rgba8UnormSrgb
+                | wgpu::TextureFormat::Bgra8Unorm
+                | wgpu::TextureFormat::Bgra8UnormSrgb
+        ) {
+            log::warn!(
+                "Reading back {:?} frames isn't supported",
+                self.surface_config.format
+            );
+            return;
+        }
+        self.should_read_frame = true;
+    }
+
+    /// Returns the pixels of the most recent frame that read_frame was called during.
+    pub fn take_read_frame(&mut self) -> Option<FramePixels> {
+        self.read_frame.take()
+    }
+
+    /// Records a copy of an 8bit four-channel texture into a mappable buffer. Returns the buffer
+    /// and its padded row size.
+    fn copy_frame_to_buffer(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+    ) -> (wgpu::Buffer, u32) {
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = (texture.width() * 4).div_ceil(align) * align;
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            size: (padded_bytes_per_row * texture.height()) as u64,
+            mapped_at_creation: false,
+        });
+
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(texture.height()),
+                },
+            },
+            texture.size(),
+        );
+
+        (buffer, padded_bytes_per_row)
     }
 
     pub fn finish_frame(&mut self) {
         let mut frame_objects = std::mem::take(&mut self.frame_objects).unwrap();
         frame_objects.render_pass = None; // Finish the render pass
 
+        let frame_readback = if std::mem::take(&mut self.should_read_frame) {
+            let texture = match (&frame_objects.surface_texture, &self.target) {
+                (Some(surface_texture), _) => &surface_texture.texture,
+                (None, RenderTarget::Offscreen(texture)) => texture,
+                (None, RenderTarget::Surface(_)) => unreachable!(),
+            };
+            Some(Self::copy_frame_to_buffer(
+                &self.device,
+                &mut frame_objects.command_encoder,
+                texture,
+            ))
+        } else {
+            None
+        };
+
+        let timestamp_readback = self
+            .timer
+            .as_mut()
+            .and_then(|timer| timer.resolve(&self.device, &mut frame_objects.command_encoder));
+
         let finished_command_buffer = frame_objects.command_encoder.finish();
         self.queue.submit(std::iter::once(finished_command_buffer));
 
-        std::mem::swap(&mut self.idle_uniforms, &mut self.busy_uniforms);
+        if let Some(timer) = self.timer.as_mut() {
+            if let Some(readback) = timestamp_readback {
+                timer.map_readback(readback);
+            }
+            self.device.poll(wgpu::Maintain::Poll);
+            timer.collect();
+        }
+
+        // Reading a frame is rare, so it's fine to wait for the GPU here.
+        if let Some((buffer, padded_bytes_per_row)) = frame_readback {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            buffer
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |result| {
+                    let _ = sender.send(result);
+                });
+            self.device.poll(wgpu::Maintain::Wait);
+
+            // The browser maps buffers asynchronously, so there the frame is silently dropped.
+            if let Ok(Ok(())) = receiver.try_recv() {
+                let padded = buffer.slice(..).get_mapped_range();
+                let bgra = matches!(
+                    self.surface_config.format,
+                    wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+                );
+                self.read_frame = Some(FramePixels {
+                    width: self.surface_config.width,
+                    height: self.surface_config.height,
+                    rgba: unpad_frame_rows(
+                        &padded,
+                        self.surface_config.width,
+                        self.surface_config.height,
+                        padded_bytes_per_row,
+                        bgra,
+                    ),
+                });
+            } else {
+                log::warn!("Failed to read the frame back");
+            }
+        }
 
-        frame_objects.surface_texture.present();
+        if let Some(surface_texture) = frame_objects.surface_texture {
+            surface_texture.present();
+        }
     }
 
-    pub fn render_mesh(&mut self, mesh: &Mesh, matrix: &Mat4, color: Option<Vec4>) {
-        let uniform = match self.idle_uniforms.pop() {
-            Some(m) => m,
-            None => Uniform::new(&self.device, &self.uniform_bindgroup_layout),
+    fn aspect_ratio_transform(&self) -> Mat4 {
+        Mat4::from_scale(Vec3::new(1.0 / self.aspect_ratio(), 1.0, 1.0))
+    }
+
+    /// Writes a draw's uniforms to the next slot in the buffer and returns the slot's offset.
+    fn write_uniforms(&mut self, matrix: &Mat4, color: &Vec4) -> u32 {
+        // Draws already recorded keep the old buffer alive, so growing mid-frame is safe.
+        if self.uniform_count == self.uniforms.capacity {
+            self.uniforms = UniformBuffer::new(
+                &self.device,
+                &self.uniform_bindgroup_layout,
+                self.uniforms.capacity * 2,
+            );
+            self.uniform_count = 0;
+        }
+        let uniform_offset = self.uniform_count * self.uniforms.stride;
+        self.uniform_count += 1;
+
+        self.queue.write_buffer(
+            &self.uniforms.buffer,
+            uniform_offset as u64,
+            &UniformBuffer::bytes(matrix, color, self.exposure, &self.fog),
+        );
+        uniform_offset
+    }
+
+    /// Fills the target with the cubemap as seen through the matrix, which is the same
+    /// transform that the scene's meshes are rendered with. Only the matrix's rotation and
+    /// projection matter, so the sky stays at an infinite distance. Draw it before the scene,
+    /// which is always in front of it.
+    pub fn render_skybox(&mut self, cubemap_id: usize, matrix: &Mat4) {
+        let clip_to_scene = (self.aspect_ratio_transform() * *matrix).inverse();
+        let uniform_offset = self.write_uniforms(&clip_to_scene, &Vec4::ONE);
+
+        let render_pass = self
+            .frame_objects
+            .as_mut()
+            .unwrap()
+            .render_pass
+            .as_mut()
+            .unwrap();
+        render_pass.set_pipeline(&self.skybox_pipeline);
+        render_pass.set_bind_group(0, &self.uniforms.bindgroup, &[uniform_offset]);
+        render_pass.set_bind_group(1, &self.cubemaps[cubemap_id].bindgroup, &[]);
+        render_pass.draw(0..3, 0..1);
+        self.command_count += 4;
+
+        // The next mesh has to rebind the pipeline and texture that the sky replaced.
+        self.bound_pipeline = None;
+        self.bound_texture = None;
+        self.render_count += 1;
+    }
+
+    /// Draws a textured quad centered on the position that always faces the camera, with the
+    /// same matrix as the scene's meshes. The size is in the scene's units along the screen's
+    /// horizontal and vertical, which are found at the position through the matrix so that
+    /// perspective and panning don't skew the quad. The aspect ratio is corrected afterwards
+    /// like any other mesh, so the quad keeps the proportions of the size.
+    pub fn render_billboard(
+        &mut self,
+        texture_id: usize,
+        world_pos: Vec3,
+        size: Vec2,
+        matrix: &Mat4,
+    ) {
+        // The direction in the scene that moves the position along a clip space axis without
+        // changing its depth, including the change to w that a perspective projection makes.
+        let inverse = matrix.inverse();
+        let screen_axis = |axis: Vec4| {
+            let direction = inverse * axis;
+            (direction.truncate() - world_pos * direction.w).normalize_or_zero()
         };
+        let right = screen_axis(Vec4::X);
+        let up = screen_axis(Vec4::Y);
+
+        let model = Mat4::from_cols(
+            (right * size.x).extend(0.0),
+            (up * size.y).extend(0.0),
+            right.cross(up).extend(0.0),
+            world_pos.extend(1.0),
+        );
+
+        let mut quad = self.billboard_quad.take().unwrap();
+        quad.texture = texture_id;
+        self.render_mesh(&quad, &(*matrix * model), None);
+        self.billboard_quad = Some(quad);
+    }
 
-        // Write the uniform to its wgpu buffer
+    /// Draws the mesh with the given transform into normalized coordinates. The optional color
+    /// tints the whole draw: the shader multiplies it with the texture and vertex colors, so
+    /// meshes without either (which use the white texture and white vertex colors) come out
+    /// exactly the tint color, and None leaves the mesh's colors unchanged.
+    pub fn render_mesh(&mut self, mesh: &Mesh, matrix: &Mat4, color: Option<Vec4>) {
         let color = match color {
             Some(c) => c,
             None => Vec4::new(1.0, 1.0, 1.0, 1.0),
         };
-        let aspect_ratio_transform =
-            Mat4::from_scale(Vec3::new(1.0 / self.aspect_ratio(), 1.0, 1.0));
-        self.queue.write_buffer(
-            &uniform.buffer,
-            0,
-            &uniform.as_bytes(&(aspect_ratio_transform * *matrix), &color),
-        );
+        let uniform_offset =
+            self.write_uniforms(&(self.aspect_ratio_transform() * *matrix), &color);
 
         let mut render_pass = self
             .frame_objects
@@ -689,17 +2027,266 @@ impl<'a> Gpu<'a> {
             .as_mut()
             .unwrap();
 
-        render_pass.set_vertex_buffer(0, mesh.positions.slice(..));
-        render_pass.set_vertex_buffer(1, mesh.vert_colors.slice(..));
-        render_pass.set_vertex_buffer(2, mesh.uvs.slice(..));
-        render_pass.set_bind_group(0, &uniform.bindgroup, &[]);
+        if self.bound_pipeline != Some((mesh.lines, self.blend_mode)) {
+            let variants = (self.pipeline_variants)(&self.pipelines);
+            let pipelines = if mesh.lines {
+                &variants.lines
+            } else {
+                &variants.triangles
+            };
+            render_pass.set_pipeline(&pipelines[self.blend_mode as usize]);
+            self.bound_pipeline = Some((mesh.lines, self.blend_mode));
+            self.command_count += 1;
+        }
+
+        // Drawing the same mesh repeatedly, like a cube per atom, only rebinds the uniforms.
+        let mesh_id = mesh.positions.global_id();
+        if self.bound_mesh != Some(mesh_id) {
+            render_pass.set_vertex_buffer(0, mesh.positions.slice(..));
+            render_pass.set_vertex_buffer(1, mesh.vert_colors.slice(..));
+            render_pass.set_vertex_buffer(2, mesh.uvs.slice(..));
+            self.bound_mesh = Some(mesh_id);
+            self.command_count += 3;
+        }
+
+        render_pass.set_bind_group(0, &self.uniforms.bindgroup, &[uniform_offset]);
 
-        let texture_bindgroup = &self.textures[mesh.texture].bindgroup;
-        render_pass.set_bind_group(1, texture_bindgroup, &[]);
+        if self.bound_texture != Some(mesh.texture) {
+            let texture_bindgroup = &self.textures[mesh.texture]
+                .as_ref()
+                .expect("Texture was destroyed")
+                .bindgroup;
+            render_pass.set_bind_group(1, texture_bindgroup, &[]);
+            self.bound_texture = Some(mesh.texture);
+            self.command_count += 1;
+        }
 
         render_pass.draw(0..mesh.vert_count as u32, 0..1);
+        self.command_count += 2;
 
-        self.busy_uniforms.push(uniform);
         self.render_count += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::cube_triangles;
+
+    /// A 64x32 offscreen GPU. Machines without any adapter, like some CI runners, can't create
+    /// one, so the tests that need it are skipped there.
+    fn headless_gpu() -> Option<Gpu<'static>> {
+        let gpu = Gpu::new_headless(64, 32);
+        if gpu.is_none() {
+            eprintln!("Skipping a GPU test because no adapter is available");
+        }
+        gpu
+    }
+
+    /// The RGBA bytes of the pixel at x, y in a frame read back with read_frame.
+    fn pixel(frame: &FramePixels, x: u32, y: u32) -> &[u8] {
+        let i = ((y * frame.width + x) * 4) as usize;
+        &frame.rgba[i..i + 4]
+    }
+
+    #[test]
+    fn test_headless_frame() {
+        let Some(mut gpu) = headless_gpu() else {
+            return;
+        };
+        assert_eq!(gpu.aspect_ratio(), 2.0);
+
+        let mesh = Mesh::new(&cube_triangles(), None, None, &gpu);
+        assert!(gpu.begin_frame().unwrap());
+        gpu.depth_test(true);
+        gpu.render_mesh(&mesh, &Mat4::IDENTITY, None);
+        gpu.read_frame();
+        gpu.finish_frame();
+
+        // The cube covers the top right quadrant of the clip volume, and its front face is white.
+        let frame = gpu.take_read_frame().unwrap();
+        assert_eq!(pixel(&frame, 40, 8), &[255, 255, 255, 255]);
+        assert_eq!(pixel(&frame, 8, 24), &[0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_viewport() {
+        let Some(mut gpu) = headless_gpu() else {
+            return;
+        };
+
+        // In the right half of the window, the cube fills the top right quadrant of that half.
+        let right_half = Viewport {
+            x: 32,
+            y: 0,
+            width: 32,
+            height: 32,
+        };
+        let mesh = Mesh::new(&cube_triangles(), None, None, &gpu);
+        assert!(gpu.begin_frame().unwrap());
+        gpu.set_viewport(
+            right_half.x,
+            right_half.y,
+            right_half.width,
+            right_half.height,
+        );
+        assert_eq!(gpu.aspect_ratio(), 1.0);
+        gpu.depth_test(true);
+        gpu.render_mesh(&mesh, &Mat4::IDENTITY, None);
+        gpu.read_frame();
+        gpu.finish_frame();
+
+        let frame = gpu.take_read_frame().unwrap();
+        assert_eq!(pixel(&frame, 56, 8), &[255, 255, 255, 255]);
+        assert_eq!(pixel(&frame, 40, 8), &[0, 0, 0, 255]);
+
+        // The window's right edge is the viewport's right edge, and the viewport's middle is
+        // three quarters of the way across the window.
+        let window_pos = Vec2::new(2.0, 0.5);
+        let viewport_pos = gpu.window_to_viewport(&window_pos, &right_half);
+        assert!(viewport_pos.abs_diff_eq(Vec2::new(1.0, 0.5), 1e-5));
+        assert!(gpu
+            .viewport_to_window(&Vec2::ZERO, &right_half)
+            .abs_diff_eq(Vec2::new(1.0, 0.0), 1e-5));
+    }
+
+    #[test]
+    fn test_read_texture() {
+        let Some(mut gpu) = headless_gpu() else {
+            return;
+        };
+
+        // 3 pixels wide, so the rows need padding to the copy alignment.
+        let pixels: Vec<u8> = (0..3 * 2 * 4).map(|i| i * 10).collect();
+        let texture = gpu
+            .create_texture(3, 2, false, WrapMode::default(), 1)
+            .unwrap();
+        gpu.write_rgba_texture(texture, &pixels);
+        assert_eq!(gpu.read_texture(texture), pixels);
+    }
+
+    #[test]
+    fn test_texture_too_large() {
+        let Some(mut gpu) = headless_gpu() else {
+            return;
+        };
+
+        let max_size = gpu.max_texture_size() as usize;
+        assert!(gpu
+            .create_texture(max_size + 1, 1, false, WrapMode::default(), 1)
+            .is_err());
+        assert!(gpu
+            .create_texture(max_size, 1, false, WrapMode::default(), 1)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_mesh_bounds() {
+        let Some(gpu) = headless_gpu() else {
+            return;
+        };
+
+        let mesh = Mesh::new(&cube_triangles(), None, None, &gpu);
+        assert_eq!(mesh.bounds(), Aabb::new(Vec3::ZERO, Vec3::ONE));
+
+        let offset: Vec<Vec3> = cube_triangles()
+            .iter()
+            .map(|pos| *pos * 2.0 - Vec3::ONE)
+            .collect();
+        let mesh = Mesh::new(&offset, None, None, &gpu);
+        assert_eq!(mesh.bounds(), Aabb::new(-Vec3::ONE, Vec3::ONE));
+    }
+
+    #[test]
+    fn test_failed_timestamp_readback() {
+        let Some(mut gpu) = headless_gpu() else {
+            return;
+        };
+        let Some(timer) = gpu.timer.as_mut() else {
+            return;
+        };
+
+        let readback = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            size: GpuTimer::BUFFER_SIZE,
+            mapped_at_creation: false,
+        });
+        let failed = Arc::new(AtomicU8::new(GpuTimer::READBACK_FAILED));
+        timer.pending_readbacks.push_back((readback, failed));
+        timer.collect();
+
+        // The failed readback mustn't stay at the front of the queue and stall every later one.
+        assert!(timer.pending_readbacks.is_empty());
+        assert_eq!(timer.frame_time, None);
+    }
+
+    #[test]
+    fn test_skybox() {
+        let Some(mut gpu) = headless_gpu() else {
+            return;
+        };
+
+        let red = [255, 0, 0, 255];
+        let green = [0, 255, 0, 255];
+        let blue = [0, 0, 255, 255];
+        let white = [255; 4];
+        let faces = [&red, &green, &blue, &red, &white, &green].map(|face| face.as_slice());
+        let cubemap = gpu.create_cubemap(faces, 1).unwrap();
+
+        // Without a rotation the view looks along +Z, and turning it a quarter around Y makes
+        // it look along -X.
+        for (matrix, face) in [
+            (Mat4::IDENTITY, white),
+            (Mat4::from_rotation_y(std::f32::consts::FRAC_PI_2), green),
+        ] {
+            assert!(gpu.begin_frame().unwrap());
+            gpu.render_skybox(cubemap, &matrix);
+            gpu.read_frame();
+            gpu.finish_frame();
+
+            let frame = gpu.take_read_frame().unwrap();
+            assert_eq!(&frame.rgba[..4], &face);
+            assert_eq!(&frame.rgba[frame.rgba.len() - 4..], &face);
+        }
+    }
+
+    #[test]
+    fn test_billboard() {
+        let Some(mut gpu) = headless_gpu() else {
+            return;
+        };
+
+        // Turned a radian away, a flat quad would be about half as wide, but the billboard
+        // turns with the camera and keeps its full width of 0.5, which is 8 pixels after the
+        // aspect ratio correction. The position is chosen to land in the middle of the frame.
+        let matrix = Mat4::from_rotation_y(1.0);
+        let position = matrix.inverse().transform_point3(Vec3::new(0.0, 0.0, 0.5));
+        assert!(gpu.begin_frame().unwrap());
+        gpu.render_billboard(WHITE_TEXTURE_ID, position, Vec2::splat(0.5), &matrix);
+        gpu.read_frame();
+        gpu.finish_frame();
+
+        let frame = gpu.take_read_frame().unwrap();
+        let is_lit = |x: u32, y: u32| pixel(&frame, x, y)[0] > 0;
+        assert!(is_lit(32, 16));
+        assert!(is_lit(35, 16));
+        assert!(is_lit(28, 19));
+        assert!(!is_lit(37, 16));
+        assert!(!is_lit(32, 21));
+    }
+
+    #[test]
+    fn test_unpad_frame_rows() {
+        // Two 1-pixel rows, each padded to 8 bytes, in BGRA order.
+        let padded = [1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8, 0, 0, 0, 0];
+        assert_eq!(
+            unpad_frame_rows(&padded, 1, 2, 8, true),
+            vec![3, 2, 1, 255, 7, 6, 5, 255]
+        );
+        assert_eq!(
+            unpad_frame_rows(&padded, 1, 2, 8, false),
+            vec![1, 2, 3, 255, 5, 6, 7, 255]
+        );
+    }
+}
@@ -1,19 +1,177 @@
-use crate::math::{cube_triangles, transform_2d};
+use crate::camera::Lens;
+use crate::gpu::{BlendMode, WrapMode};
+use crate::math::{
+    cube_edges, cube_triangles, plane_ray_intersection, sphere_triangles, transform_2d, Aabb,
+    CheckFace,
+};
 use crate::prelude::*;
+use glam::IVec2;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Error, ErrorKind, Read, Write};
 
 pub const GRID_SIZE: usize = 8;
+pub const CAN_SAVE: bool = cfg!(not(target_arch = "wasm32")); // The browser has no filesystem
+const AO_BRIGHTNESS: [f32; 4] = [1.0, 0.75, 0.6, 0.45]; // By a vertex's number of occluders
+const GRID_SAVE_PATH: &str = "nopush/grid_save.json";
+const EDITOR_SAVE_PATH: &str = "nopush/editor_save.json";
+const SAVE_VERSION: u32 = 2; // Bump this and extend GridSave::migrate when Atom changes
+const ORTHO_BESIDE_2D_X: f32 = -0.8; // The 3D view's offset when it shares the window with 2D
+const CELL_2D_SIZE: f32 = 0.9; // The side of a cell's square in the 2D view, leaving a gap
 
-#[derive(Default, Copy, Clone)]
+/// Which views of the grid are shown. Painting with the mouse only works in the 2D view.
+#[derive(Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ViewMode {
+    #[default]
+    Both,
+    TwoD,
+    ThreeD,
+    SideBySide, // Both, with the 2D view in the left half of the window and 3D in the right
+}
+
+/// An axis of the grid, like the one that painting is mirrored along.
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Axis {
+    X,
+    Y,
+}
+
+/// Editor settings shared between the debugger UI and the game. Everything except the
+/// single-frame requests is saved between sessions.
+#[derive(Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct EditorState {
     pub current_atom: Atom,
+    #[serde(skip)]
     pub should_reload: bool,
     pub is_playing: bool,
+    #[serde(skip)]
     pub should_step: bool,
+    pub perspective: bool,
+    pub lens: Lens,
+    #[serde(skip)]
+    pub should_reset_camera: bool,
+    pub show_fps: bool,
+    pub exposure_stops: f32, // The 3D view's exposure as a power of two, so 0 is unchanged
+    pub fog_color: Vec3,
+    pub fog_density: f32, // 0 turns the 3D view's fog off
+    pub depth_prepass: bool,
+    pub show_solids: bool, // The solid and liquid cubes
+    pub show_floor: bool,
+    pub show_bounds: bool,
+    pub show_gas: bool,
+    pub show_pressure_spheres: bool,
+    pub pressure_scale: f32, // Multiplies the pressure spheres' radii, which are capped at a cell
+    pub show_pressure_contour: bool,
+    pub contour_pressure: f32,
+    pub auto_pressure_range: bool, // Fit the heatmap to the grid's pressures every frame
+    pub pressure_range: (f32, f32), // The pressures at the heatmap's blue and red ends
+    pub view_mode: ViewMode,
+    pub camera_smoothing: f32, // Seconds for the camera to ease most of the way to its goal
+    pub seed: u64,
+    #[serde(skip)]
+    pub should_reseed: bool,
+    #[serde(skip)]
+    pub should_clear: bool,
+    #[serde(skip)]
+    pub should_randomize: bool,
+    pub solid_fraction: f32, // The chance of each cell being solid when randomizing
+    pub brush_radius: u32,   // In cells, where 0 paints one cell at a time
+    pub mirror_axis: Option<Axis>, // Painting is mirrored across a plane along this axis
+    pub mirror_plane: f32,   // In cells along the mirror axis, in half cell steps
+    #[serde(skip)]
+    pub should_mirror: Option<Axis>,
+    #[serde(skip)]
+    pub should_rotate: bool,
+    #[serde(skip)]
+    pub should_translate: Option<IVec2>,
+    #[serde(skip)]
+    pub should_add_keyframe: bool,
+    #[serde(skip)]
+    pub should_clear_path: bool,
+    #[serde(skip)]
+    pub touring: bool, // Play the camera path instead of following input
+}
+
+impl Default for EditorState {
+    fn default() -> Self {
+        Self {
+            current_atom: Atom::default(),
+            should_reload: false,
+            is_playing: false,
+            should_step: false,
+            perspective: false,
+            lens: Lens::default(),
+            should_reset_camera: false,
+            show_fps: false,
+            exposure_stops: 0.0,
+            fog_color: Vec3::new(0.5, 0.6, 0.7),
+            fog_density: 0.0,
+            depth_prepass: false,
+            show_solids: true,
+            show_floor: false,
+            show_bounds: false,
+            show_gas: false,
+            show_pressure_spheres: false,
+            pressure_scale: 1.0,
+            show_pressure_contour: false,
+            contour_pressure: 50.0,
+            auto_pressure_range: false,
+            pressure_range: (-100.0, 100.0),
+            view_mode: ViewMode::default(),
+            camera_smoothing: 0.1,
+            seed: 0,
+            should_reseed: false,
+            should_clear: false,
+            should_randomize: false,
+            solid_fraction: 0.3,
+            brush_radius: 0,
+            mirror_axis: None,
+            mirror_plane: GRID_SIZE as f32 / 2.0,
+            should_mirror: None,
+            should_rotate: false,
+            should_translate: None,
+            should_add_keyframe: false,
+            should_clear_path: false,
+            touring: false,
+        }
+    }
+}
+
+impl EditorState {
+    pub fn load() -> Self {
+        fn load_inner() -> Result<EditorState, std::io::Error> {
+            let mut file = File::open(EDITOR_SAVE_PATH)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            Ok(serde_json::from_str(&contents)?)
+        }
+
+        match load_inner() {
+            Ok(editor_state) => editor_state,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    log::warn!("Failed to load {}: {}", EDITOR_SAVE_PATH, e);
+                }
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        if !CAN_SAVE {
+            return;
+        }
+        let json = serde_json::to_string(self).expect("Failed to serialize editor state");
+
+        let mut file = File::create(EDITOR_SAVE_PATH).expect("Failed to create file");
+        file.write_all(json.as_bytes())
+            .expect("Failed to write to file");
+    }
 }
 
+/// Saved in the grid save file, so give any field added to a variant #[serde(default)] so that
+/// older saves without it still load. Changes that defaults can't cover need a GridSave version.
 #[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Atom {
     Gas(f32),
@@ -27,41 +185,168 @@ impl Default for Atom {
     }
 }
 
+/// The grid's save file. Version 1 saves were the bare rows of atoms without this header.
+#[derive(Serialize, Deserialize)]
+struct GridSave {
+    version: u32,
+    atoms: Vec<Vec<Atom>>,
+}
+
+impl GridSave {
+    fn parse(contents: &str) -> Result<Self, Error> {
+        let value: serde_json::Value = serde_json::from_str(contents)?;
+        let save = if value.is_array() {
+            GridSave {
+                version: 1,
+                atoms: serde_json::from_value(value)?,
+            }
+        } else {
+            serde_json::from_value(value)?
+        };
+        save.migrate()
+    }
+
+    /// Upgrades an older save to the current version one version at a time, filling anything
+    /// that's new with defaults.
+    fn migrate(mut self) -> Result<Self, Error> {
+        if self.version > SAVE_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Save version {} is newer than this build's version {}",
+                    self.version, SAVE_VERSION
+                ),
+            ));
+        }
+
+        if self.version == 1 {
+            // Version 2 only added the header.
+            self.version = 2;
+        }
+
+        Ok(self)
+    }
+}
+
+/// The number of occupied cells that darken a face's vertex, given the two cells beside it and
+/// the one diagonally across from it, all in front of the face. Two sides fully occlude the
+/// vertex whatever the corner is.
+fn vertex_occlusion(side_a: bool, side_b: bool, corner: bool) -> usize {
+    if side_a && side_b {
+        3
+    } else {
+        side_a as usize + side_b as usize + corner as usize
+    }
+}
+
+/// Maps a pressure to a heatmap color that runs from blue at the bottom of the range, through
+/// green, to red at the top. Pressures outside the range take the nearest end's color.
+pub fn pressure_to_color(pressure: f32, (min, max): (f32, f32)) -> Vec4 {
+    let t = if max > min {
+        ((pressure - min) / (max - min)).clamp(0.0, 1.0)
+    } else {
+        0.5
+    };
+
+    let blue = Vec4::new(0.0, 0.0, 1.0, 1.0);
+    let green = Vec4::new(0.0, 1.0, 0.0, 1.0);
+    let red = Vec4::new(1.0, 0.0, 0.0, 1.0);
+    if t < 0.5 {
+        blue.lerp(green, t * 2.0)
+    } else {
+        green.lerp(red, t * 2.0 - 1.0)
+    }
+}
+
+/// Meshes created the first time the grid is rendered rather than every frame. The surface
+/// and contour are rebuilt only when the atoms they show have changed.
+struct GridMeshes {
+    cell_2d: Mesh,
+    cube: Mesh,
+    cube_edges: Mesh,
+    floor: Mesh,
+    floor_tiles: Mesh,
+    sphere: Mesh,
+    unit_line: Mesh,       // From the origin to x = 1
+    surface: Option<Mesh>, // The solid and liquid cubes' faces, or None if there aren't any
+    contour: Option<Mesh>, // The pressure contour's lines, or None if there aren't any
+}
+
+impl GridMeshes {
+    fn new(gpu: &mut Gpu) -> Self {
+        let cell_2d_verts = [
+            Vec2::new(0.0, 0.0),
+            Vec2::new(CELL_2D_SIZE, 0.0),
+            Vec2::new(0.0, CELL_2D_SIZE),
+            Vec2::new(0.0, CELL_2D_SIZE),
+            Vec2::new(CELL_2D_SIZE, 0.0),
+            Vec2::new(CELL_2D_SIZE, CELL_2D_SIZE),
+        ];
+
+        Self {
+            cell_2d: Mesh::new_2d(&cell_2d_verts, None, None, gpu),
+            cube: Mesh::new(&cube_triangles(), None, None, gpu),
+            cube_edges: Mesh::new_lines(&cube_edges(), None, gpu),
+            floor: Mesh::new_lines(&Grid::floor_lines(), None, gpu),
+            floor_tiles: Grid::floor_tiles_mesh(gpu),
+            sphere: Mesh::new(&sphere_triangles(8, 16), None, None, gpu),
+            unit_line: Mesh::new_lines(&[Vec3::ZERO, Vec3::X], None, gpu),
+            surface: None,
+            contour: None,
+        }
+    }
+}
+
 pub struct Grid {
     atoms: Vec<Vec<Atom>>,
     transform: Mat4,
-    mover: f32,
+    ortho_transform: Mat4,
+    meshes: Option<GridMeshes>,
+    surface_dirty: bool, // Whether a solid or liquid atom may have changed since the last mesh
+    contour_iso: Option<f32>, // The cached contour's iso pressure, or None if it's out of date
+    pressure_range: (f32, f32),
+    rng: StdRng, // All of the simulation's randomness comes from here, so that it's repeatable
+    seed: u64,
 }
 
 impl Grid {
-    fn new() -> Self {
+    pub fn new() -> Self {
         let scale = 0.1;
         let translate_z = 0.5; // The viable range is 0 to 1, so put it in the middle.
         Self {
             transform: Mat4::from_translation(Vec3::new(0.0, 0.0, translate_z))
                 * Mat4::from_scale(Vec3::new(scale, scale, scale * 3.0)),
             atoms: vec![vec![Atom::default(); GRID_SIZE]; GRID_SIZE],
-            mover: 0.0,
+            ortho_transform: Self::ortho_transform_at(ORTHO_BESIDE_2D_X),
+            meshes: None,
+            surface_dirty: true,
+            contour_iso: None,
+            pressure_range: EditorState::default().pressure_range,
+            rng: StdRng::seed_from_u64(0),
+            seed: 0,
         }
     }
 
     pub fn load() -> Self {
         fn load_inner() -> Result<Vec<Vec<Atom>>, std::io::Error> {
-            let mut file = File::open("nopush/grid_save.json")?;
+            let mut file = File::open(GRID_SAVE_PATH)?;
             let mut contents = String::new();
             file.read_to_string(&mut contents)?;
-            Ok(serde_json::from_str(&contents)?)
+            Ok(GridSave::parse(&contents)?.atoms)
         }
 
         let mut grid = Self::new();
 
         grid.atoms = match load_inner() {
             Ok(atoms) => {
-                println!("Loading atoms from file");
+                log::info!("Loading atoms from file");
                 atoms
             }
-            Err(_) => {
-                println!("Creating new atoms");
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    log::warn!("Failed to load {}: {}", GRID_SAVE_PATH, e);
+                }
+                log::info!("Creating new atoms");
                 vec![vec![Atom::default(); GRID_SIZE]; GRID_SIZE]
             }
         };
@@ -69,6 +354,151 @@ impl Grid {
         grid
     }
 
+    /// Restarts the simulation's random number sequence from the seed.
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.seed = seed;
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    /// Sets every cell to gas at zero pressure.
+    pub fn clear(&mut self) {
+        for column in &mut self.atoms {
+            column.fill(Atom::default());
+        }
+        self.atoms_changed();
+    }
+
+    /// Makes each cell solid with the given probability, and gas at zero pressure otherwise.
+    /// The cells come from the grid's seeded random number generator.
+    pub fn randomize(&mut self, solid_fraction: f32) {
+        let probability = solid_fraction.clamp(0.0, 1.0) as f64;
+        for x in 0..GRID_SIZE {
+            for y in 0..GRID_SIZE {
+                self.atoms[x][y] = if self.rng.gen_bool(probability) {
+                    Atom::Solid
+                } else {
+                    Atom::default()
+                };
+            }
+        }
+        self.atoms_changed();
+    }
+
+    /// Reflects the whole grid along the axis.
+    pub fn mirror(&mut self, axis: Axis) {
+        match axis {
+            Axis::X => self.atoms.reverse(),
+            Axis::Y => {
+                for column in &mut self.atoms {
+                    column.reverse();
+                }
+            }
+        }
+        self.atoms_changed();
+    }
+
+    /// Rotates the whole grid a quarter turn counterclockwise, as seen in the 2D view.
+    pub fn rotate_90(&mut self) {
+        let old = self.atoms.clone();
+        for x in 0..GRID_SIZE {
+            for y in 0..GRID_SIZE {
+                self.atoms[x][y] = old[y][GRID_SIZE - 1 - x];
+            }
+        }
+        self.atoms_changed();
+    }
+
+    /// Moves the whole grid by the offset in cells, wrapping cells that leave one side around
+    /// to the other so that nothing is lost.
+    pub fn translate(&mut self, offset: IVec2) {
+        let size = GRID_SIZE as i32;
+        let old = self.atoms.clone();
+        for x in 0..GRID_SIZE {
+            for y in 0..GRID_SIZE {
+                let to_x = (x as i32 + offset.x).rem_euclid(size) as usize;
+                let to_y = (y as i32 + offset.y).rem_euclid(size) as usize;
+                self.atoms[to_x][to_y] = old[x][y];
+            }
+        }
+        self.atoms_changed();
+    }
+
+    /// Writes the grid as PNG slices that other tools can edit, one per layer, named slice_0.png
+    /// and so on. The grid only has one layer. Each pixel is a cell, oriented like the 2D view,
+    /// and its color is the atom: black for gas, white for solid and blue for liquid. Pressure
+    /// isn't kept.
+    pub fn export_slices(&self, dir: &str) -> Result<(), image::ImageError> {
+        std::fs::create_dir_all(dir)?;
+
+        let mut rgba = Vec::with_capacity(GRID_SIZE * GRID_SIZE * 4);
+        for row in 0..GRID_SIZE {
+            for x in 0..GRID_SIZE {
+                // Image rows run down, but the 2D view's y runs up.
+                rgba.extend_from_slice(&match self.atoms[x][GRID_SIZE - 1 - row] {
+                    Atom::Gas(_) => [0, 0, 0, 255],
+                    Atom::Solid => [255, 255, 255, 255],
+                    Atom::Liquid => [0, 0, 255, 255],
+                });
+            }
+        }
+
+        image::save_buffer(
+            format!("{}/slice_0.png", dir),
+            &rgba,
+            GRID_SIZE as u32,
+            GRID_SIZE as u32,
+            image::ExtendedColorType::Rgba8,
+        )
+    }
+
+    /// Reads slices written by export_slices, which must be the grid's size. Colors are matched
+    /// loosely so that edited images still load: bright pixels are solid, blue ones are liquid
+    /// and the rest are gas at zero pressure.
+    pub fn import_slices(&mut self, dir: &str) -> Result<(), image::ImageError> {
+        let image = image::open(format!("{}/slice_0.png", dir))?.to_rgba8();
+        if image.dimensions() != (GRID_SIZE as u32, GRID_SIZE as u32) {
+            return Err(image::ImageError::Parameter(
+                image::error::ParameterError::from_kind(
+                    image::error::ParameterErrorKind::DimensionMismatch,
+                ),
+            ));
+        }
+
+        for (x, row, pixel) in image.enumerate_pixels() {
+            let [r, g, b, _] = pixel.0;
+            let bright = |channel: u8| channel > 127;
+            self.atoms[x as usize][GRID_SIZE - 1 - row as usize] =
+                match (bright(r), bright(g), bright(b)) {
+                    (true, true, true) => Atom::Solid,
+                    (false, _, true) => Atom::Liquid,
+                    _ => Atom::default(),
+                };
+        }
+        self.atoms_changed();
+        Ok(())
+    }
+
+    pub fn inspect(&self, (x, y): (usize, usize)) -> Atom {
+        self.atoms[x][y]
+    }
+
+    pub fn set(&mut self, (x, y): (usize, usize), atom: Atom) {
+        self.atoms[x][y] = atom;
+        self.atoms_changed();
+    }
+
+    pub fn atoms(&self) -> &[Vec<Atom>] {
+        &self.atoms
+    }
+
     pub fn modify_under_path(&mut self, start: &Vec2, end: &Vec2, editor: &EditorState) {
         // TODO: I'm not sure when the best time to transform from Vec2 to (usize, usize) is. I think this fn shouldn't be aware of the editor either. The pub interface to the grid can convert Vec2 to (usize, usize) and inspect the editor before getting here.
         let start = transform_2d(&start, &self.transform.inverse());
@@ -83,54 +513,147 @@ impl Grid {
             end.y.clamp(0.0, GRID_SIZE as f32 - 1.0) as usize,
         );
 
-        for (x, y) in Grid::atoms_on_path(start, end) {
+        // A set, so that cells on the mirror plane or under overlapping brushes are edited once.
+        let mut cells = HashSet::new();
+        for cell in Grid::atoms_on_path(start, end) {
+            for cell in Grid::brush_cells(cell, editor.brush_radius) {
+                cells.insert(cell);
+                if let Some(axis) = editor.mirror_axis {
+                    cells.extend(Grid::mirrored_cell(cell, axis, editor.mirror_plane));
+                }
+            }
+        }
+
+        for (x, y) in cells {
             self.atoms[x][y] = editor.current_atom;
         }
+        self.atoms_changed();
+    }
+
+    /// The cell's reflection across the plane along the axis, or None if it's outside the grid.
+    /// A cell that the plane passes through the middle of is its own reflection.
+    fn mirrored_cell((x, y): (usize, usize), axis: Axis, plane: f32) -> Option<(usize, usize)> {
+        let reflect = |v: usize| {
+            let reflected = (2.0 * plane - v as f32 - 1.0).round();
+            (0.0..GRID_SIZE as f32)
+                .contains(&reflected)
+                .then_some(reflected as usize)
+        };
+        match axis {
+            Axis::X => Some((reflect(x)?, y)),
+            Axis::Y => Some((x, reflect(y)?)),
+        }
+    }
+
+    /// Draws the plane that painting is mirrored across as a faint line in the 2D view.
+    pub fn render_mirror_plane(&mut self, axis: Axis, plane: f32, gpu: &mut Gpu) {
+        self.create_meshes(gpu);
+
+        // The cells' squares are a little smaller than a cell, so shift into the gap between.
+        let plane = plane - 0.05;
+        let position = match axis {
+            Axis::X => {
+                Mat4::from_translation(Vec3::new(plane, 0.0, 0.0))
+                    * Mat4::from_rotation_z(std::f32::consts::FRAC_PI_2)
+            }
+            Axis::Y => Mat4::from_translation(Vec3::new(0.0, plane, 0.0)),
+        };
+        let m = position * Mat4::from_scale(Vec3::splat(GRID_SIZE as f32));
+
+        gpu.depth_test(false);
+        gpu.render_mesh(
+            &self.meshes().unit_line,
+            &(self.transform * m),
+            Some(Vec4::new(1.0, 1.0, 1.0, 0.4)),
+        );
+    }
+
+    /// The cells within the radius of the center cell, as a disc clipped to the grid. A radius
+    /// of 0 is just the center.
+    fn brush_cells((x, y): (usize, usize), radius: u32) -> Vec<(usize, usize)> {
+        let radius = radius as i32;
+        let mut cells = vec![];
+        for dx in -radius..=radius {
+            for dy in -radius..=radius {
+                let (cx, cy) = (x as i32 + dx, y as i32 + dy);
+                let in_grid = |v: i32| v >= 0 && v < GRID_SIZE as i32;
+                if dx * dx + dy * dy <= radius * radius && in_grid(cx) && in_grid(cy) {
+                    cells.push((cx as usize, cy as usize));
+                }
+            }
+        }
+        cells
+    }
+
+    /// Highlights the cells that painting at the position would change in the 2D view, if the
+    /// position is over the grid.
+    pub fn render_brush(&mut self, normalized_pos: Vec2, radius: u32, gpu: &mut Gpu) {
+        let pos = transform_2d(&normalized_pos, &self.transform.inverse());
+        let in_grid = |v: f32| (0.0..GRID_SIZE as f32).contains(&v);
+        if !in_grid(pos.x) || !in_grid(pos.y) {
+            return;
+        }
+
+        self.create_meshes(gpu);
+        gpu.depth_test(false);
+        for (x, y) in Self::brush_cells((pos.x as usize, pos.y as usize), radius) {
+            let m = Mat4::from_translation(Vec3::new(x as f32, y as f32, 0.0));
+            gpu.render_mesh(
+                &self.meshes().cell_2d,
+                &(self.transform * m),
+                Some(Vec4::new(1.0, 1.0, 1.0, 0.3)),
+            );
+        }
     }
 
     pub fn save(&self) {
-        let json = serde_json::to_string(&self.atoms).expect("Failed to serialize grid");
+        if !CAN_SAVE {
+            return;
+        }
+        let save = GridSave {
+            version: SAVE_VERSION,
+            atoms: self.atoms.clone(),
+        };
+        let json = serde_json::to_string(&save).expect("Failed to serialize grid");
 
-        let mut file = File::create("nopush/grid_save.json").expect("Failed to create file");
+        let mut file = File::create(GRID_SAVE_PATH).expect("Failed to create file");
         file.write_all(json.as_bytes())
             .expect("Failed to write to file");
 
-        println!("Grid saved to nopush/grid_save.json");
+        log::info!("Grid saved to {}", GRID_SAVE_PATH);
     }
 
+    /// The cells on a straight line between two cells, inclusive, using Bresenham's algorithm.
     fn atoms_on_path(start: (usize, usize), end: (usize, usize)) -> Vec<(usize, usize)> {
-        let mut path: Vec<(i32, i32)> = vec![];
+        let mut path = vec![];
 
-        let mut mover = (start.0 as i32, start.1 as i32);
+        let (mut x, mut y) = (start.0 as i32, start.1 as i32);
         let end = (end.0 as i32, end.1 as i32);
 
-        path.push(mover);
+        let dx = (end.0 - x).abs();
+        let dy = -(end.1 - y).abs();
+        let step_x = if x < end.0 { 1 } else { -1 };
+        let step_y = if y < end.1 { 1 } else { -1 };
+        let mut error = dx + dy;
 
         loop {
-            if mover == end {
+            path.push((x as usize, y as usize));
+            if (x, y) == end {
                 break;
             }
 
-            if (mover.0 - end.0).abs() > (mover.1 - end.1).abs() {
-                if mover.0 < end.0 {
-                    mover.0 += 1;
-                } else {
-                    mover.0 -= 1;
-                }
-            } else {
-                if mover.1 < end.1 {
-                    mover.1 += 1;
-                } else {
-                    mover.1 -= 1;
-                }
+            let doubled_error = error * 2;
+            if doubled_error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled_error <= dx {
+                error += dx;
+                y += step_y;
             }
-
-            path.push(mover);
         }
 
-        path.into_iter()
-            .map(|(x, y)| (x as usize, y as usize))
-            .collect::<Vec<(usize, usize)>>()
+        path
     }
 
     fn mut_gas_pressures(&mut self, x: usize, y: usize) -> Vec<&mut f32> {
@@ -156,22 +679,96 @@ impl Grid {
         pressures
     }
 
-    pub fn update(&mut self, editor: &EditorState) {
+    /// Applies the editor's commands and then advances the simulation by the number of fixed
+    /// steps.
+    pub fn update(&mut self, editor: &EditorState, steps: u32) {
         if editor.should_reload {
             self.atoms = Self::load().atoms;
+            self.atoms_changed();
+        }
+
+        if editor.should_reseed {
+            self.reseed(editor.seed);
         }
 
-        if editor.is_playing || editor.should_step {
-            self.update_gas_with_2x2_equilibrium();
+        if editor.should_clear {
+            self.clear();
+        }
+        if editor.should_randomize {
+            self.randomize(editor.solid_fraction);
+        }
+        if let Some(axis) = editor.should_mirror {
+            self.mirror(axis);
+        }
+        if editor.should_rotate {
+            self.rotate_90();
+        }
+        if let Some(offset) = editor.should_translate {
+            self.translate(offset);
         }
 
-        self.mover += 0.05;
+        for _ in 0..steps {
+            self.step();
+        }
+
+        self.pressure_range = if editor.auto_pressure_range {
+            self.gas_pressure_range()
+                .unwrap_or(EditorState::default().pressure_range)
+        } else {
+            editor.pressure_range
+        };
+    }
+
+    /// The sum of every gas cell's pressure, which stays constant while no gas is painted and
+    /// none reaches the grid's edges, where it's erased.
+    pub fn total_pressure(&self) -> f32 {
+        self.atoms
+            .iter()
+            .flatten()
+            .map(|atom| match atom {
+                Atom::Gas(pressure) => *pressure,
+                _ => 0.0,
+            })
+            .sum()
+    }
+
+    /// The lowest and highest gas pressures, or None if there's no gas.
+    fn gas_pressure_range(&self) -> Option<(f32, f32)> {
+        let mut range: Option<(f32, f32)> = None;
+        for column in &self.atoms {
+            for atom in column {
+                if let Atom::Gas(p) = atom {
+                    range = Some(range.map_or((*p, *p), |(min, max)| (min.min(*p), max.max(*p))));
+                }
+            }
+        }
+        range
+    }
+
+    /// Advances the simulation by one fixed step, without any of the editor's commands.
+    pub fn step(&mut self) {
+        self.update_gas_with_2x2_equilibrium();
+        self.contour_iso = None;
+    }
+
+    /// Marks the meshes built from the atoms as out of date.
+    fn atoms_changed(&mut self) {
+        self.surface_dirty = true;
+        self.contour_iso = None;
     }
 
     fn update_gas_with_2x2_equilibrium(&mut self) {
         debug_assert!(GRID_SIZE % 2 == 0);
 
         let mut reach_local_equilibrium = |x: usize, y: usize| {
+            // Two gas cells that only touch at a corner are walled off from each other by the
+            // other two cells, so gas mustn't leak diagonally between them.
+            let is_gas = |dx: usize, dy: usize| matches!(self.atoms[x + dx][y + dy], Atom::Gas(_));
+            let (a, b, c, d) = (is_gas(0, 0), is_gas(1, 0), is_gas(0, 1), is_gas(1, 1));
+            if (a && d && !b && !c) || (b && c && !a && !d) {
+                return;
+            }
+
             let pressures = self.mut_gas_pressures(x, y);
 
             let mut pressure_total = 0.0;
@@ -200,59 +797,530 @@ impl Grid {
 
         // Erase edges
         for x in 0..GRID_SIZE {
-            self.atoms[x][0] = Atom::Gas(0.0);
-            self.atoms[x][GRID_SIZE - 1] = Atom::Gas(0.0);
+            self.erase_cell(x, 0);
+            self.erase_cell(x, GRID_SIZE - 1);
         }
         for y in 0..GRID_SIZE {
-            self.atoms[0][y] = Atom::Gas(0.0);
-            self.atoms[GRID_SIZE - 1][y] = Atom::Gas(0.0);
+            self.erase_cell(0, y);
+            self.erase_cell(GRID_SIZE - 1, y);
         }
     }
 
-    pub fn render_2d(&self, gpu: &mut Gpu) {
-        gpu.depth_test(false);
+    fn erase_cell(&mut self, x: usize, y: usize) {
+        if !matches!(self.atoms[x][y], Atom::Gas(_)) {
+            self.surface_dirty = true;
+        }
+        self.atoms[x][y] = Atom::Gas(0.0);
+        self.contour_iso = None;
+    }
 
-        let verts = vec![
-            Vec2::new(0.0, 0.0),
-            Vec2::new(0.9, 0.0),
-            Vec2::new(0.0, 0.9),
-            Vec2::new(0.0, 0.9),
-            Vec2::new(0.9, 0.0),
-            Vec2::new(0.9, 0.9),
+    fn atom_color(&self, atom: &Atom) -> Vec4 {
+        match atom {
+            Atom::Gas(p) => pressure_to_color(*p, self.pressure_range),
+            Atom::Solid => Vec4::new(0.0, 1.0, 0.0, 1.0),
+            Atom::Liquid => Vec4::new(0.0, 1.0, 1.0, 1.0),
+        }
+    }
+
+    /// The position of a cell's unit cube in the ortho scene, which is centered on the origin so
+    /// that the camera orbits around the middle of the grid.
+    fn cell_scene_position(x: usize, y: usize) -> Vec3 {
+        let half_size = GRID_SIZE as f32 / 2.0;
+        Vec3::new(x as f32 - half_size, y as f32 - half_size, -0.5)
+    }
+
+    /// The ortho scene's bounds around every non-gas atom, or the whole grid if there are none.
+    pub fn occupied_bounds(&self) -> Aabb {
+        let mut bounds: Option<Aabb> = None;
+        for x in 0..GRID_SIZE {
+            for y in 0..GRID_SIZE {
+                if let Atom::Gas(_) = self.atoms[x][y] {
+                    continue;
+                }
+                let min = Self::cell_scene_position(x, y);
+                let cell = Aabb::new(min, min + Vec3::ONE);
+                bounds = Some(bounds.map_or(cell, |b| b.union(&cell)));
+            }
+        }
+
+        bounds.unwrap_or_else(Self::bounds)
+    }
+
+    /// The ortho scene's bounds around the whole grid.
+    pub fn bounds() -> Aabb {
+        let min = Self::cell_scene_position(0, 0);
+        Aabb::new(
+            min,
+            min + Vec3::new(GRID_SIZE as f32, GRID_SIZE as f32, 1.0),
+        )
+    }
+
+    pub fn ortho_transform(&self) -> Mat4 {
+        self.ortho_transform
+    }
+
+    /// Depth is squashed so that the grid fits in the clip volume at any rotation.
+    fn ortho_transform_at(x: f32) -> Mat4 {
+        let scale = 0.1;
+        let translate_z = 0.5;
+        Mat4::from_translation(Vec3::new(x, 0.0, translate_z))
+            * Mat4::from_scale(Vec3::new(scale, scale, scale * 0.5))
+    }
+
+    /// Moves the 3D view to the middle of its viewport when it has one to itself, or back off
+    /// to the left of the 2D grid when they share the window.
+    pub fn center_ortho_view(&mut self, centered: bool) {
+        let x = if centered { 0.0 } else { ORTHO_BESIDE_2D_X };
+        self.ortho_transform = Self::ortho_transform_at(x);
+    }
+
+    /// Lines a cell apart in the plane under the grid, spanning the grid's width and an equal
+    /// depth, so that there's a spatial reference when few cells are occupied.
+    fn floor_lines() -> Vec<Vec3> {
+        let half_size = GRID_SIZE as f32 / 2.0;
+        let mut positions = Vec::with_capacity((GRID_SIZE + 1) * 4);
+        for i in 0..=GRID_SIZE {
+            let offset = i as f32 - half_size;
+            positions.push(Vec3::new(offset, -half_size, -half_size));
+            positions.push(Vec3::new(offset, -half_size, half_size));
+            positions.push(Vec3::new(-half_size, -half_size, offset));
+            positions.push(Vec3::new(half_size, -half_size, offset));
+        }
+        positions
+    }
+
+    /// A checkerboard quad just below the floor lines, with a cell per texel. The UVs run past
+    /// 1 so that the 2x2 texture repeats across the floor.
+    fn floor_tiles_mesh(gpu: &mut Gpu) -> Mesh {
+        let dark = [30u8, 30, 30, 255];
+        let light = [50u8, 50, 50, 255];
+        let texture = gpu
+            .create_texture(2, 2, false, WrapMode::Repeat, 1)
+            .expect("2x2 textures are always supported");
+        gpu.write_rgba_texture(texture, &[dark, light, light, dark].concat());
+
+        let half_size = GRID_SIZE as f32 / 2.0;
+        let y = -half_size - 0.01; // Below the lines so they don't z-fight
+        let corner = |x: f32, z: f32| Vec3::new(x * half_size, y, z * half_size);
+        let positions = [
+            corner(-1.0, -1.0),
+            corner(1.0, -1.0),
+            corner(-1.0, 1.0),
+            corner(-1.0, 1.0),
+            corner(1.0, -1.0),
+            corner(1.0, 1.0),
         ];
 
-        let mesh = Mesh::new_2d(&verts, None, None, gpu);
+        let repeats = GRID_SIZE as f32 / 2.0;
+        let uv = |u: f32, v: f32| Vec2::new(u, v) * repeats;
+        let uvs = [
+            uv(0.0, 0.0),
+            uv(1.0, 0.0),
+            uv(0.0, 1.0),
+            uv(0.0, 1.0),
+            uv(1.0, 0.0),
+            uv(1.0, 1.0),
+        ];
+
+        Mesh::new(&positions, None, Some((texture, &uvs)), gpu)
+    }
+
+    /// Creates the meshes if this is the first render. Call this before borrowing them.
+    fn create_meshes(&mut self, gpu: &mut Gpu) {
+        if self.meshes.is_none() {
+            self.meshes = Some(GridMeshes::new(gpu));
+        }
+    }
+
+    fn meshes(&self) -> &GridMeshes {
+        self.meshes.as_ref().expect("create_meshes wasn't called")
+    }
+
+    /// Draws gas cells as translucent cubes that are more opaque the further their pressure is
+    /// from zero. Call this after the opaque cubes, because the cubes are blended without writing
+    /// depth.
+    pub fn render_gas(&mut self, camera: &Camera, gpu: &mut Gpu) {
+        self.create_meshes(gpu);
+        let mesh = &self.meshes().cube;
+        let view = camera.transform(&self.ortho_transform);
 
+        let mut cubes = vec![];
         for x in 0..GRID_SIZE {
             for y in 0..GRID_SIZE {
-                let color = match self.atoms[x][y] {
-                    Atom::Gas(v) => Vec4::new(v * 0.01, 0.0, 1.0 - v * 0.01, 1.0),
-                    Atom::Solid => Vec4::new(0.0, 1.0, 0.0, 1.0),
-                    Atom::Liquid => Vec4::new(0.0, 1.0, 1.0, 1.0),
+                let atom = &self.atoms[x][y];
+                if let Atom::Gas(pressure) = atom {
+                    let alpha = (pressure.abs() / 100.0).min(1.0) * 0.5;
+                    if alpha > 0.0 {
+                        let m = Mat4::from_translation(Self::cell_scene_position(x, y));
+                        let color = self.atom_color(atom).truncate().extend(alpha);
+                        cubes.push((view * m, color));
+                    }
+                }
+            }
+        }
+
+        // Blending needs back to front order. Depth increases away from the camera, so sort by
+        // the depth of each cube's center.
+        let depth = |matrix: &Mat4| matrix.project_point3(Vec3::splat(0.5)).z;
+        cubes.sort_by(|(a, _), (b, _)| depth(b).total_cmp(&depth(a)));
+
+        gpu.depth_test_read_only();
+        for (matrix, color) in cubes {
+            gpu.render_mesh(mesh, &matrix, Some(color));
+        }
+    }
+
+    /// Draws a glowing sphere in each gas cell, additively blended so that overlapping glows
+    /// brighten rather than hide each other, which also means they needn't be sorted. The
+    /// sphere grows with the pressure's distance from zero.
+    pub fn render_pressure_spheres(&mut self, camera: &Camera, pressure_scale: f32, gpu: &mut Gpu) {
+        self.create_meshes(gpu);
+        let mesh = &self.meshes().sphere;
+        let view = camera.transform(&self.ortho_transform);
+
+        gpu.depth_test_read_only();
+        gpu.set_blend_mode(BlendMode::Additive);
+        for x in 0..GRID_SIZE {
+            for y in 0..GRID_SIZE {
+                let atom = &self.atoms[x][y];
+                if let Atom::Gas(pressure) = atom {
+                    let radius = (pressure.abs() * pressure_scale / 100.0).min(1.0) * 0.5;
+                    if radius > 0.0 && radius.is_finite() {
+                        let center = Self::cell_scene_position(x, y) + Vec3::splat(0.5);
+                        let m =
+                            Mat4::from_translation(center) * Mat4::from_scale(Vec3::splat(radius));
+                        let color = self.atom_color(atom).truncate().extend(0.5);
+                        gpu.render_mesh(mesh, &(view * m), Some(color));
+                    }
+                }
+            }
+        }
+        gpu.set_blend_mode(BlendMode::Alpha);
+    }
+
+    /// The cell under a point in normalized coordinates in the 3D view, found by intersecting
+    /// the view ray with the plane through the middle of the grid's cells.
+    pub fn hovered_cell(&self, camera: &Camera, normalized_pos: Vec2) -> Option<(usize, usize)> {
+        let (origin, direction) = camera.ray(&self.ortho_transform, normalized_pos);
+        let hit = plane_ray_intersection(Vec3::Z, Vec3::ZERO, origin, direction, CheckFace::Both)?;
+
+        let half_size = GRID_SIZE as f32 / 2.0;
+        let x = (hit.x + half_size).floor();
+        let y = (hit.y + half_size).floor();
+        let in_grid = |v: f32| v >= 0.0 && v < GRID_SIZE as f32;
+        if in_grid(x) && in_grid(y) {
+            Some((x as usize, y as usize))
+        } else {
+            None
+        }
+    }
+
+    /// The center of the cell's cube in normalized coordinates, for placing UI next to it.
+    pub fn cell_center(&self, camera: &Camera, (x, y): (usize, usize)) -> Vec2 {
+        let center = Self::cell_scene_position(x, y) + Vec3::splat(0.5);
+        camera
+            .transform(&self.ortho_transform)
+            .project_point3(center)
+            .truncate()
+    }
+
+    /// Outlines the cell's cube slightly larger than the cube itself. Depth testing is disabled
+    /// so the outline shows through any cubes in front of it.
+    pub fn render_outline(&mut self, camera: &Camera, (x, y): (usize, usize), gpu: &mut Gpu) {
+        self.create_meshes(gpu);
+
+        let scale = 1.05;
+        let center_offset = Vec3::splat(0.5);
+        let m = Mat4::from_translation(Self::cell_scene_position(x, y) + center_offset)
+            * Mat4::from_scale(Vec3::splat(scale))
+            * Mat4::from_translation(-center_offset);
+
+        gpu.depth_test(false);
+        gpu.render_mesh(
+            &self.meshes().cube_edges,
+            &(camera.transform(&self.ortho_transform) * m),
+            Some(Vec4::new(1.0, 1.0, 0.0, 1.0)),
+        );
+    }
+
+    /// Outlines the whole grid in faint white, so its extents are visible when the cells at its
+    /// edges are empty.
+    pub fn render_bounds(&mut self, camera: &Camera, gpu: &mut Gpu) {
+        self.create_meshes(gpu);
+
+        let bounds = Self::bounds();
+        let m = Mat4::from_translation(bounds.min) * Mat4::from_scale(bounds.size());
+
+        gpu.depth_test(true);
+        gpu.render_mesh(
+            &self.meshes().cube_edges,
+            &(camera.transform(&self.ortho_transform) * m),
+            Some(Vec4::new(1.0, 1.0, 1.0, 0.3)),
+        );
+    }
+
+    pub fn render_floor(&mut self, camera: &Camera, gpu: &mut Gpu) {
+        self.create_meshes(gpu);
+
+        gpu.depth_test(true);
+        let view = camera.transform(&self.ortho_transform);
+        gpu.render_mesh(&self.meshes().floor_tiles, &view, None);
+        gpu.render_mesh(
+            &self.meshes().floor,
+            &view,
+            Some(Vec4::new(0.3, 0.3, 0.3, 1.0)),
+        );
+    }
+
+    /// The gas pressure at a cell, where solids and liquids count as zero.
+    fn pressure_at(&self, x: usize, y: usize) -> f32 {
+        match self.atoms[x][y] {
+            Atom::Gas(pressure) => pressure,
+            _ => 0.0,
+        }
+    }
+
+    /// Traces where the pressure field crosses the iso pressure with marching squares, sampling
+    /// at the cells and interpolating linearly between them. Returns pairs of positions for a
+    /// line mesh, in cell units with cell (x, y) at (x, y).
+    fn pressure_contour(&self, iso: f32) -> Vec<Vec2> {
+        // The segments for each combination of corners above the iso pressure, as pairs of
+        // edges. Corners are bits 0 to 3 anticlockwise from the bottom left, and edge i runs
+        // from corner i to the next. The two saddle cases keep their corners separate.
+        const SEGMENTS: [&[(usize, usize)]; 16] = [
+            &[],
+            &[(3, 0)],
+            &[(0, 1)],
+            &[(3, 1)],
+            &[(1, 2)],
+            &[(3, 0), (1, 2)],
+            &[(0, 2)],
+            &[(3, 2)],
+            &[(2, 3)],
+            &[(0, 2)],
+            &[(0, 1), (2, 3)],
+            &[(1, 2)],
+            &[(1, 3)],
+            &[(0, 1)],
+            &[(3, 0)],
+            &[],
+        ];
+
+        let mut lines = vec![];
+        for x in 0..GRID_SIZE - 1 {
+            for y in 0..GRID_SIZE - 1 {
+                let corners = [(x, y), (x + 1, y), (x + 1, y + 1), (x, y + 1)];
+                let values = corners.map(|(x, y)| self.pressure_at(x, y));
+                let case = (0..4).fold(0, |case, i| case | ((values[i] > iso) as usize) << i);
+
+                // Where the contour crosses the edge, with shared edges ordered the same way
+                // in both squares so that the segments meet exactly.
+                let crossing = |edge: usize| {
+                    let (i, j) = (edge, (edge + 1) % 4);
+                    let (i, j) = if edge >= 2 { (j, i) } else { (i, j) };
+                    let point = |i: usize| Vec2::new(corners[i].0 as f32, corners[i].1 as f32);
+                    let t = (iso - values[i]) / (values[j] - values[i]);
+                    point(i).lerp(point(j), t)
                 };
+
+                for (a, b) in SEGMENTS[case] {
+                    lines.push(crossing(*a));
+                    lines.push(crossing(*b));
+                }
+            }
+        }
+        lines
+    }
+
+    /// Outlines the regions of the 2D view where the gas pressure is above the iso pressure. The
+    /// contour is only retraced after a step, an edit or a change of iso pressure.
+    pub fn render_pressure_contour(&mut self, iso: f32, gpu: &mut Gpu) {
+        self.create_meshes(gpu);
+        if self.contour_iso != Some(iso) {
+            let lines = self.pressure_contour(iso);
+            let contour = (!lines.is_empty()).then(|| {
+                let positions: Vec<Vec3> = lines.iter().map(|pos| pos.extend(0.0)).collect();
+                Mesh::new_lines(&positions, None, gpu)
+            });
+            self.meshes.as_mut().unwrap().contour = contour;
+            self.contour_iso = Some(iso);
+        }
+        let Some(mesh) = &self.meshes().contour else {
+            return;
+        };
+
+        // The samples are at the middle of each cell's square.
+        let m = Mat4::from_translation(Vec3::new(CELL_2D_SIZE, CELL_2D_SIZE, 0.0) * 0.5);
+        gpu.depth_test(false);
+        gpu.render_mesh(mesh, &(self.transform * m), Some(Vec4::ONE));
+    }
+
+    pub fn render_2d(&mut self, gpu: &mut Gpu) {
+        self.create_meshes(gpu);
+        let mesh = &self.meshes().cell_2d;
+
+        gpu.depth_test(false);
+
+        for x in 0..GRID_SIZE {
+            for y in 0..GRID_SIZE {
+                let color = self.atom_color(&self.atoms[x][y]);
                 let m = Mat4::from_translation(Vec3::new(x as f32, y as f32, 0.0));
-                gpu.render_mesh(&mesh, &(self.transform * m), Some(color));
+                gpu.render_mesh(mesh, &(self.transform * m), Some(color));
             }
         }
     }
 
-    pub fn render_ortho(&self, gpu: &mut Gpu) {
-        gpu.depth_test(true);
+    /// The visible faces of the solid and liquid cubes as a triangle list with vertex colors.
+    /// Faces between two of them are culled, and neighboring faces of the same kind of atom are
+    /// merged greedily into larger quads.
+    fn surface_triangles(&self) -> (Vec<Vec3>, Vec<Vec4>) {
+        let mut positions = vec![];
+        let mut colors = vec![];
+        // Occlusion is per corner, in the order origin, origin + u, origin + v, origin + u + v.
+        let mut push_quad = |origin: Vec3, u: Vec3, v: Vec3, color: Vec4, occlusion: [usize; 4]| {
+            let [a, b, c, d] = occlusion
+                .map(|occluders| (color.truncate() * AO_BRIGHTNESS[occluders]).extend(color.w));
+            positions.extend_from_slice(&[origin, origin + u, origin + v]);
+            positions.extend_from_slice(&[origin + v, origin + u, origin + u + v]);
+            colors.extend_from_slice(&[a, b, c, c, b, d]);
+        };
+
+        // Faces are hidden by any non-gas neighbor, but only merge with the same kind of atom.
+        let exposed = |x: i32, y: i32| {
+            let in_grid = |v: i32| v >= 0 && v < GRID_SIZE as i32;
+            !in_grid(x) || !in_grid(y) || matches!(self.atoms[x as usize][y as usize], Atom::Gas(_))
+        };
+        let occupied = |cell: IVec2| !exposed(cell.x, cell.y);
+
+        for atom in [Atom::Solid, Atom::Liquid] {
+            let color = self.atom_color(&atom);
+            let is_atom = |x: usize, y: usize| self.atoms[x][y] == atom;
+
+            // The top and bottom faces, as the largest rectangles that fit. Nothing is above or
+            // below the grid's single layer, so they're never occluded.
+            let mut covered = [[false; GRID_SIZE]; GRID_SIZE];
+            for y in 0..GRID_SIZE {
+                for x in 0..GRID_SIZE {
+                    if covered[x][y] || !is_atom(x, y) {
+                        continue;
+                    }
+                    let fits = |x: usize, y: usize| !covered[x][y] && is_atom(x, y);
+                    let width = (x..GRID_SIZE).take_while(|&x| fits(x, y)).count();
+                    let height = (y..GRID_SIZE)
+                        .take_while(|&y| (x..x + width).all(|x| fits(x, y)))
+                        .count();
+                    for covered_column in &mut covered[x..x + width] {
+                        covered_column[y..y + height].fill(true);
+                    }
+
+                    let origin = Self::cell_scene_position(x, y);
+                    let u = Vec3::X * width as f32;
+                    let v = Vec3::Y * height as f32;
+                    push_quad(origin, u, v, color, [0; 4]);
+                    push_quad(origin + Vec3::Z, u, v, color, [0; 4]);
+                }
+            }
+
+            // The side faces, merged into runs along the face.
+            for (normal, along) in [
+                (IVec2::NEG_X, IVec2::Y),
+                (IVec2::X, IVec2::Y),
+                (IVec2::NEG_Y, IVec2::X),
+                (IVec2::Y, IVec2::X),
+            ] {
+                // Faces on the positive side are a cell further along the normal.
+                let face_offset = normal.max(IVec2::ZERO).as_vec2().extend(0.0);
+                let mut run_start: Option<(IVec2, usize)> = None;
+                for row in 0..GRID_SIZE as i32 {
+                    for i in 0..=GRID_SIZE as i32 {
+                        let cell = along * i + along.perp().abs() * row;
+                        let has_face = i < GRID_SIZE as i32
+                            && is_atom(cell.x as usize, cell.y as usize)
+                            && exposed(cell.x + normal.x, cell.y + normal.y);
+                        match (has_face, run_start) {
+                            (true, None) => run_start = Some((cell, 1)),
+                            (true, Some((start, length))) => run_start = Some((start, length + 1)),
+                            (false, Some((start, length))) => {
+                                // Only the vertical edges at a run's ends can be occluded, by a
+                                // cell in front of the face at an inside corner. Nothing is
+                                // above or below, so that's the only side cell there can be.
+                                let end = start + along * (length as i32 - 1);
+                                let start_occlusion = vertex_occlusion(
+                                    occupied(start + normal - along),
+                                    false,
+                                    false,
+                                );
+                                let end_occlusion =
+                                    vertex_occlusion(occupied(end + normal + along), false, false);
+
+                                // Occluded end cells are split off, so their shading doesn't
+                                // stretch along the whole run.
+                                let mut pieces = vec![];
+                                let (mut first, mut last) = (0, length);
+                                if start_occlusion > 0 && last - first > 1 {
+                                    pieces.push((0, 1, start_occlusion, 0));
+                                    first += 1;
+                                }
+                                if end_occlusion > 0 && last - first > 1 {
+                                    pieces.push((length - 1, 1, 0, end_occlusion));
+                                    last -= 1;
+                                }
+                                let first_occlusion = if first == 0 { start_occlusion } else { 0 };
+                                let last_occlusion = if last == length { end_occlusion } else { 0 };
+                                pieces.push((first, last - first, first_occlusion, last_occlusion));
 
-        let mut cube_verts = cube_triangles();
+                                for (offset, count, a, b) in pieces {
+                                    let cell = start + along * offset as i32;
+                                    let origin =
+                                        Self::cell_scene_position(cell.x as usize, cell.y as usize)
+                                            + face_offset;
+                                    let u = along.as_vec2().extend(0.0) * count as f32;
+                                    push_quad(origin, u, Vec3::Z, color, [a, b, a, b]);
+                                }
+                                run_start = None;
+                            }
+                            (false, None) => (),
+                        }
+                    }
+                }
+            }
+        }
 
-        cube_verts.iter_mut().for_each(|v| {
-            *v -= Vec3::new(0.5, 0.5, 0.5);
-        });
-        let mesh = Mesh::new(&cube_verts, None, None, gpu);
+        (positions, colors)
+    }
+
+    /// Builds a single mesh of the visible faces of the solid and liquid cubes, or None if there
+    /// aren't any.
+    pub fn build_surface_mesh(&self, gpu: &Gpu) -> Option<Mesh> {
+        let (positions, colors) = self.surface_triangles();
+        if positions.is_empty() {
+            return None;
+        }
+        Some(Mesh::new(&positions, Some(&colors), None, gpu))
+    }
 
-        let rotator = {
-            let x = Mat4::from_rotation_x(self.mover);
-            let y = Mat4::from_rotation_y(self.mover * 0.3);
-            x * y
+    /// Draws the solid and liquid cubes as one greedily meshed surface, which is only rebuilt
+    /// after a solid or liquid atom may have changed.
+    pub fn render_ortho(&mut self, camera: &Camera, depth_prepass: bool, gpu: &mut Gpu) {
+        self.create_meshes(gpu);
+        if self.surface_dirty {
+            let surface = self.build_surface_mesh(gpu);
+            self.meshes.as_mut().unwrap().surface = surface;
+            self.surface_dirty = false;
+        }
+        let Some(mesh) = &self.meshes().surface else {
+            return;
         };
+        let view = camera.transform(&self.ortho_transform);
 
-        gpu.render_mesh(&mesh, &(self.transform * rotator), None);
+        if depth_prepass {
+            gpu.begin_depth_prepass();
+            gpu.render_mesh(mesh, &view, None);
+            gpu.end_depth_prepass();
+        } else {
+            gpu.depth_test(true);
+        }
+        gpu.render_mesh(mesh, &view, None);
     }
 }
 
@@ -260,9 +1328,339 @@ impl Grid {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_load_v1_save() {
+        let v1 = r#"[[{"Gas":2.5},"Solid"],["Liquid",{"Gas":0.0}]]"#;
+        let save = GridSave::parse(v1).unwrap();
+        assert_eq!(save.version, SAVE_VERSION);
+        assert!(save.atoms[0] == [Atom::Gas(2.5), Atom::Solid]);
+        assert!(save.atoms[1] == [Atom::Liquid, Atom::Gas(0.0)]);
+
+        let current = serde_json::to_string(&save).unwrap();
+        assert!(GridSave::parse(&current).unwrap().atoms == save.atoms);
+
+        let newer = format!(r#"{{"version":{},"atoms":[]}}"#, SAVE_VERSION + 1);
+        let error = GridSave::parse(&newer).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_brush_cells() {
+        assert_eq!(Grid::brush_cells((3, 4), 0), vec![(3, 4)]);
+        assert_eq!(Grid::brush_cells((3, 4), 1).len(), 5);
+        assert_eq!(Grid::brush_cells((3, 4), 2).len(), 13);
+
+        // Cells outside the grid are clipped.
+        let corner = Grid::brush_cells((0, 0), 1);
+        assert_eq!(corner, vec![(0, 0), (0, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn test_mirrored_cell() {
+        let middle = GRID_SIZE as f32 / 2.0;
+        assert_eq!(
+            Grid::mirrored_cell((1, 2), Axis::X, middle),
+            Some((GRID_SIZE - 2, 2))
+        );
+        assert_eq!(
+            Grid::mirrored_cell((1, 2), Axis::Y, middle),
+            Some((1, GRID_SIZE - 3))
+        );
+
+        // A plane through the middle of a cell reflects it onto itself, and reflections outside
+        // the grid are dropped.
+        assert_eq!(Grid::mirrored_cell((3, 5), Axis::X, 3.5), Some((3, 5)));
+        assert_eq!(Grid::mirrored_cell((5, 5), Axis::X, 1.0), None);
+    }
+
+    #[test]
+    fn test_grid_transforms() {
+        let mut grid = Grid::new();
+        grid.randomize(0.5);
+        grid.atoms[1][2] = Atom::Gas(7.0);
+        let original = grid.atoms.clone();
+
+        for axis in [Axis::X, Axis::Y] {
+            grid.mirror(axis);
+            assert!(grid.atoms != original);
+            grid.mirror(axis);
+            assert!(grid.atoms == original);
+        }
+
+        grid.rotate_90();
+        assert!(grid.atoms[GRID_SIZE - 3][1] == Atom::Gas(7.0));
+        for _ in 0..3 {
+            grid.rotate_90();
+        }
+        assert!(grid.atoms == original);
+
+        grid.translate(IVec2::new(-2, 1));
+        assert!(grid.atoms[GRID_SIZE - 1][3] == Atom::Gas(7.0));
+        grid.translate(IVec2::new(2, GRID_SIZE as i32 - 1));
+        assert!(grid.atoms == original);
+    }
+
+    #[test]
+    fn test_slices() {
+        let dir = std::env::temp_dir().join(format!("grid_slices_{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+
+        let mut grid = Grid::new();
+        grid.atoms[1][2] = Atom::Solid;
+        grid.atoms[3][0] = Atom::Liquid;
+        grid.atoms[4][5] = Atom::Gas(50.0);
+        grid.export_slices(dir).unwrap();
+
+        let mut imported = Grid::new();
+        imported.randomize(0.5);
+        imported.import_slices(dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert!(imported.atoms[1][2] == Atom::Solid);
+        assert!(imported.atoms[3][0] == Atom::Liquid);
+        grid.atoms[4][5] = Atom::Gas(0.0); // Pressure isn't exported
+        assert!(imported.atoms == grid.atoms);
+    }
+
     #[test]
     fn test_zero_path() {
         let path = Grid::atoms_on_path((2, 2), (2, 2));
         assert_eq!(path, vec![(2, 2)]);
     }
+
+    #[test]
+    fn test_diagonal_path() {
+        let path = Grid::atoms_on_path((0, 0), (3, 3));
+        assert_eq!(path, vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+
+        let path = Grid::atoms_on_path((3, 0), (0, 3));
+        assert_eq!(path, vec![(3, 0), (2, 1), (1, 2), (0, 3)]);
+    }
+
+    #[test]
+    fn test_shallow_path() {
+        let path = Grid::atoms_on_path((0, 0), (4, 1));
+        assert_eq!(path, vec![(0, 0), (1, 0), (2, 1), (3, 1), (4, 1)]);
+
+        let path = Grid::atoms_on_path((4, 1), (0, 0));
+        assert_eq!(path.len(), 5);
+        assert_eq!(path.first(), Some(&(4, 1)));
+        assert_eq!(path.last(), Some(&(0, 0)));
+    }
+
+    #[test]
+    fn test_pressure_to_color() {
+        let range = (-100.0, 100.0);
+        assert_eq!(
+            pressure_to_color(-100.0, range),
+            Vec4::new(0.0, 0.0, 1.0, 1.0)
+        );
+        assert_eq!(pressure_to_color(0.0, range), Vec4::new(0.0, 1.0, 0.0, 1.0));
+        assert_eq!(
+            pressure_to_color(100.0, range),
+            Vec4::new(1.0, 0.0, 0.0, 1.0)
+        );
+
+        // Out of range pressures are clamped, and an empty range maps to the middle.
+        assert_eq!(
+            pressure_to_color(500.0, range),
+            Vec4::new(1.0, 0.0, 0.0, 1.0)
+        );
+        assert_eq!(
+            pressure_to_color(3.0, (3.0, 3.0)),
+            Vec4::new(0.0, 1.0, 0.0, 1.0)
+        );
+    }
+
+    #[test]
+    fn test_surface_triangles() {
+        let mut grid = Grid::new();
+        assert!(grid.surface_triangles().0.is_empty());
+
+        // A 4x4 block is a top, a bottom and four sides, rather than 16 cubes of 12 triangles.
+        for x in 2..6 {
+            for y in 1..5 {
+                grid.atoms[x][y] = Atom::Solid;
+            }
+        }
+        let (positions, colors) = grid.surface_triangles();
+        assert_eq!(positions.len(), 6 * 6);
+        assert_eq!(colors.len(), positions.len());
+
+        // A liquid cell inside the block hides the neighboring solid faces, but doesn't merge.
+        grid.atoms[3][2] = Atom::Liquid;
+        let (positions, _) = grid.surface_triangles();
+        // The hole splits the solid top and bottom into 4 rectangles each, the 4 solid sides
+        // are unchanged, and the liquid only has a top and bottom.
+        assert_eq!(positions.len() / 6, 4 * 2 + 4 + 2);
+    }
+
+    #[test]
+    fn test_surface_dirty() {
+        let mut grid = Grid::new();
+        assert!(grid.surface_dirty);
+
+        // Gas flowing doesn't change the surface, but painting a solid does.
+        grid.surface_dirty = false;
+        grid.set((3, 3), Atom::Gas(10.0));
+        grid.surface_dirty = false;
+        grid.step();
+        assert!(!grid.surface_dirty);
+        grid.set((3, 3), Atom::Solid);
+        assert!(grid.surface_dirty);
+
+        // Stepping erases the edges, which changes the surface if a solid was there.
+        grid.set((0, 3), Atom::Solid);
+        grid.surface_dirty = false;
+        grid.step();
+        assert!(grid.surface_dirty);
+    }
+
+    #[test]
+    fn test_ambient_occlusion() {
+        assert_eq!(vertex_occlusion(false, false, false), 0);
+        assert_eq!(vertex_occlusion(true, false, true), 2);
+        assert_eq!(vertex_occlusion(true, true, false), 3);
+
+        // An L of three cells, whose inside corner is at the far corner of cell (2, 2).
+        let mut grid = Grid::new();
+        grid.atoms[2][2] = Atom::Solid;
+        grid.atoms[3][2] = Atom::Solid;
+        grid.atoms[2][3] = Atom::Solid;
+        let (positions, colors) = grid.surface_triangles();
+
+        let darkest_at = |corner: Vec3| {
+            positions
+                .iter()
+                .zip(&colors)
+                .filter(|(position, _)| position.xy() == corner.xy())
+                .map(|(_, color)| color.y)
+                .fold(f32::INFINITY, f32::min)
+        };
+        let inside_corner = Grid::cell_scene_position(3, 3);
+        let exposed_corner = Grid::cell_scene_position(4, 2);
+        assert!(darkest_at(inside_corner) < darkest_at(exposed_corner));
+        assert_eq!(darkest_at(exposed_corner), 1.0);
+    }
+
+    #[test]
+    fn test_pressure_contour() {
+        let mut grid = Grid::new();
+        assert!(grid.pressure_contour(50.0).is_empty());
+
+        grid.atoms[3][4] = Atom::Gas(100.0);
+        let lines = grid.pressure_contour(50.0);
+        assert_eq!(lines.len(), 4 * 2); // A diamond around the cell
+
+        // The contour is closed, so every end of a segment is the end of another segment too.
+        for point in &lines {
+            let count = lines.iter().filter(|other| *other == point).count();
+            assert_eq!(count, 2);
+        }
+        assert!(lines.contains(&Vec2::new(3.5, 4.0)));
+    }
+
+    #[test]
+    fn test_contour_out_of_date() {
+        let mut grid = Grid::new();
+        grid.contour_iso = Some(50.0);
+        grid.set((3, 4), Atom::Gas(100.0));
+        assert_eq!(grid.contour_iso, None);
+
+        grid.contour_iso = Some(50.0);
+        grid.step();
+        assert_eq!(grid.contour_iso, None);
+    }
+
+    #[test]
+    fn test_total_pressure() {
+        let mut grid = Grid::new();
+        assert_eq!(grid.total_pressure(), 0.0);
+
+        // A ring of solid keeps the gas away from the edges, so none of it is erased.
+        for i in 1..GRID_SIZE - 1 {
+            grid.atoms[i][1] = Atom::Solid;
+            grid.atoms[i][GRID_SIZE - 2] = Atom::Solid;
+            grid.atoms[1][i] = Atom::Solid;
+            grid.atoms[GRID_SIZE - 2][i] = Atom::Solid;
+        }
+        grid.atoms[2][2] = Atom::Gas(100.0);
+        grid.atoms[4][3] = Atom::Gas(-20.0);
+        assert_eq!(grid.total_pressure(), 80.0);
+
+        let editor = EditorState::default();
+        grid.update(&editor, 100);
+        assert!((grid.total_pressure() - 80.0).abs() < 0.001);
+        assert!(grid.atoms[2][2] != Atom::Gas(100.0));
+    }
+
+    #[test]
+    fn test_clear_and_randomize() {
+        let mut grid = Grid::new();
+        grid.randomize(0.3);
+        let solids = grid.atoms.iter().flatten();
+        let fraction = solids.filter(|atom| **atom == Atom::Solid).count() as f32
+            / (GRID_SIZE * GRID_SIZE) as f32;
+        assert!((fraction - 0.3).abs() < 0.15);
+
+        grid.atoms[1][2] = Atom::Gas(10.0);
+        grid.clear();
+        assert!(grid
+            .atoms
+            .iter()
+            .flatten()
+            .all(|atom| *atom == Atom::Gas(0.0)));
+
+        grid.randomize(1.0);
+        assert!(grid.atoms.iter().flatten().all(|atom| *atom == Atom::Solid));
+    }
+
+    #[test]
+    fn test_walls_block_gas() {
+        // A diagonal wall, which gas could leak through at the corners where its cells meet.
+        let mut grid = Grid::new();
+        for i in 0..GRID_SIZE {
+            grid.atoms[i][i] = Atom::Solid;
+        }
+
+        let editor = EditorState::default();
+        for _ in 0..100 {
+            grid.atoms[4][2] = Atom::Gas(100.0); // Keep supplying gas on one side
+            grid.update(&editor, 1);
+        }
+
+        assert!(grid.pressure_at(4, 3) > 0.0);
+        for x in 0..GRID_SIZE {
+            for y in x + 1..GRID_SIZE {
+                assert_eq!(grid.pressure_at(x, y), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reseed() {
+        let mut grid = Grid::new();
+        grid.reseed(42);
+        let first: Vec<u32> = (0..4).map(|_| grid.rng().gen()).collect();
+        grid.reseed(42);
+        let second: Vec<u32> = (0..4).map(|_| grid.rng().gen()).collect();
+        assert_eq!(first, second);
+        assert_eq!(grid.seed(), 42);
+    }
+
+    #[test]
+    fn test_hovered_cell() {
+        let grid = Grid::new();
+        let camera = Camera::new();
+
+        // The grid's center is at (-0.8, 0) in normalized coordinates, and cells are 0.1 wide.
+        let hovered = |pos| grid.hovered_cell(&camera, pos);
+        assert_eq!(hovered(Vec2::new(-0.75, 0.05)), Some((4, 4)));
+        assert_eq!(hovered(Vec2::new(-1.15, -0.35)), Some((0, 0)));
+        assert_eq!(hovered(Vec2::new(0.5, 0.0)), None);
+
+        // A cell's projected center picks the cell itself.
+        let center = grid.cell_center(&camera, (3, 5));
+        assert_eq!(hovered(center), Some((3, 5)));
+    }
 }
@@ -0,0 +1,219 @@
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+pub struct DoubleClickDetector {
+    pub max_interval: Duration,
+    pub max_distance: f32, // In normalized coordinates
+    last_press: Option<(Instant, Vec2)>,
+}
+
+impl DoubleClickDetector {
+    pub fn new() -> Self {
+        Self {
+            max_interval: Duration::from_millis(300),
+            max_distance: 0.02,
+            last_press: None,
+        }
+    }
+
+    /// Returns true if this press completes a double-click.
+    pub fn press(&mut self, pos: Vec2, time: Instant) -> bool {
+        if let Some((prev_time, prev_pos)) = self.last_press {
+            if time.duration_since(prev_time) <= self.max_interval
+                && pos.distance(prev_pos) <= self.max_distance
+            {
+                // Forget the press so that a triple-click doesn't produce two double-clicks.
+                self.last_press = None;
+                return true;
+            }
+        }
+
+        self.last_press = Some((time, pos));
+        false
+    }
+}
+
+/// Turns a left press, the following mouse movement and the release into drag gesture events.
+pub struct DragTracker {
+    start: Option<Vec2>,
+}
+
+impl DragTracker {
+    pub fn new() -> Self {
+        Self { start: None }
+    }
+
+    /// Returns the gesture event that the given raw event produces, if any.
+    pub fn track(&mut self, event: &Event) -> Option<Event> {
+        match event {
+            Event::LeftClickPressed(pos) => {
+                self.start = Some(*pos);
+                Some(Event::DragStart(*pos))
+            }
+            Event::MousePos(pos) => self.start.map(|start| Event::Drag {
+                start,
+                current: *pos,
+            }),
+            Event::LeftClickReleased(pos) => self
+                .start
+                .take()
+                .map(|start| Event::DragEnd { start, end: *pos }),
+            _ => None,
+        }
+    }
+}
+
+/// The events pushed during one frame of a recording, numbered from the recording's start, and
+/// the simulation steps the frame took. Playback repeats the steps rather than deriving them
+/// from its own frame times, so the simulation replays exactly.
+#[derive(Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub frame: u64,
+    #[serde(default)]
+    pub events: Vec<Event>,
+    #[serde(default)] // Recordings from before steps were stored replay without stepping
+    pub steps: u32,
+}
+
+/// A session's input, saved so that it can be replayed. Frames without events or steps aren't
+/// stored.
+#[derive(Default, Serialize, Deserialize)]
+pub struct InputRecording {
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl InputRecording {
+    /// Appends an event to the frame, which must be the latest frame recorded so far or later.
+    pub fn record(&mut self, frame: u64, event: Event) {
+        match self.frames.last_mut() {
+            Some(last) if last.frame == frame => last.events.push(event),
+            _ => self.frames.push(RecordedFrame {
+                frame,
+                events: vec![event],
+                steps: 0,
+            }),
+        }
+    }
+
+    /// Stores the simulation steps that the frame took, which must be the latest frame
+    /// recorded so far or later.
+    pub fn record_steps(&mut self, frame: u64, steps: u32) {
+        if steps == 0 {
+            return;
+        }
+        match self.frames.last_mut() {
+            Some(last) if last.frame == frame => last.steps = steps,
+            _ => self.frames.push(RecordedFrame {
+                frame,
+                events: vec![],
+                steps,
+            }),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, std::io::Error> {
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), std::io::Error> {
+        let json = serde_json::to_string(self)?;
+        fs::write(path, json)
+    }
+}
+
+/// Feeds a recording's events back frame by frame.
+pub struct InputPlayback {
+    recording: InputRecording,
+    next: usize, // The index of the next frame in the recording to play
+}
+
+impl InputPlayback {
+    pub fn new(recording: InputRecording) -> Self {
+        Self { recording, next: 0 }
+    }
+
+    /// Returns the events recorded for the frame, and any earlier frames not yet returned.
+    pub fn events_for_frame(&mut self, frame: u64) -> Vec<Event> {
+        let mut events = vec![];
+        while let Some(recorded) = self.recording.frames.get(self.next) {
+            if recorded.frame > frame {
+                break;
+            }
+            events.extend(recorded.events.iter().cloned());
+            self.next += 1;
+        }
+        events
+    }
+
+    /// The simulation steps that the frame took while it was recorded.
+    pub fn steps_for_frame(&self, frame: u64) -> u32 {
+        let frames = &self.recording.frames;
+        frames
+            .binary_search_by_key(&frame, |recorded| recorded.frame)
+            .map_or(0, |i| frames[i].steps)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.recording.frames.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_double_click() {
+        let mut detector = DoubleClickDetector::new();
+        let start = Instant::now();
+        let pos = Vec2::new(0.1, 0.2);
+
+        assert!(!detector.press(pos, start));
+        assert!(detector.press(pos, start + Duration::from_millis(200)));
+
+        // A third press starts a new sequence rather than double-clicking again.
+        assert!(!detector.press(pos, start + Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn test_double_click_too_slow_or_too_far() {
+        let mut detector = DoubleClickDetector::new();
+        let start = Instant::now();
+        let pos = Vec2::new(0.1, 0.2);
+
+        assert!(!detector.press(pos, start));
+        assert!(!detector.press(pos, start + Duration::from_millis(400)));
+        assert!(!detector.press(
+            pos + Vec2::new(0.5, 0.0),
+            start + Duration::from_millis(500)
+        ));
+    }
+
+    #[test]
+    fn test_drag_phases() {
+        let mut tracker = DragTracker::new();
+        let a = Vec2::new(0.0, 0.0);
+        let b = Vec2::new(0.5, 0.0);
+        let c = Vec2::new(0.5, 0.5);
+
+        // Moving without a press is not a drag.
+        assert!(tracker.track(&Event::MousePos(a)).is_none());
+
+        assert!(matches!(
+            tracker.track(&Event::LeftClickPressed(a)),
+            Some(Event::DragStart(start)) if start == a
+        ));
+        assert!(matches!(
+            tracker.track(&Event::MousePos(b)),
+            Some(Event::Drag { start, current }) if start == a && current == b
+        ));
+        assert!(matches!(
+            tracker.track(&Event::LeftClickReleased(c)),
+            Some(Event::DragEnd { start, end }) if start == a && end == c
+        ));
+
+        assert!(tracker.track(&Event::MousePos(c)).is_none());
+    }
+}
@@ -0,0 +1,19 @@
+// The game is a library so that benchmarks can use it, and main.rs is the window around it.
+
+pub mod camera;
+pub mod config;
+pub mod debugger;
+pub mod game;
+#[cfg(feature = "gamepad")]
+pub mod gamepad;
+#[cfg(feature = "gltf")]
+pub mod gltf_loader;
+pub mod gpu;
+pub mod grid;
+pub mod input;
+pub mod math;
+pub mod obj;
+pub mod prelude;
+
+const WINDOW_WIDTH: u32 = 1200;
+const WINDOW_HEIGHT: u32 = 675;
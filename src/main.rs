@@ -2,60 +2,151 @@
 #![allow(unused)]
 #![allow(dead_code)]
 
-mod debugger;
-mod game;
-mod gpu;
-mod grid;
-mod math;
-mod prelude;
-
-use game::Game;
-use prelude::*;
+use game::config::Config;
+use game::game::Game;
+use game::gpu::GpuInitError;
+use game::prelude::*;
 use std::sync::Arc;
 use winit::{
     application::ApplicationHandler,
-    dpi::LogicalSize,
-    event::{ElementState, MouseButton, WindowEvent},
-    event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
-    monitor::VideoModeHandle,
-    window::{Fullscreen, Window, WindowId},
+    dpi::{LogicalSize, PhysicalPosition},
+    error::ExternalError,
+    event::{DeviceEvent, DeviceId, ElementState, MouseButton, MouseScrollDelta, WindowEvent},
+    event_loop::{ActiveEventLoop, ControlFlow, EventLoop, EventLoopProxy},
+    keyboard::{KeyCode, PhysicalKey},
+    monitor::{MonitorHandle, VideoModeHandle},
+    window::{CursorGrabMode, Fullscreen, Window, WindowId},
 };
 
-const WINDOW_WIDTH: u32 = 1200;
-const WINDOW_HEIGHT: u32 = 675;
+const PIXELS_PER_SCROLL_LINE: f32 = 20.0;
+const CAPTURE_CURSOR_KEY: KeyCode = KeyCode::KeyC;
+const FULLSCREEN_KEY: KeyCode = KeyCode::F11;
 
-struct App<'a> {
+/// Sent to the event loop once the Gpu has been created, which the browser does asynchronously.
+type GpuCreated = Result<Gpu<'static>, GpuInitError>;
+
+struct App {
+    config: Config,
+    proxy: EventLoopProxy<GpuCreated>,
     window: Option<Arc<Window>>,
-    gpu: Option<Gpu<'a>>,
+    gpu: Option<Gpu<'static>>,
     game: Option<Game>,
     mouse_pos: Vec2,
+    modifiers: ModifiersState,
+    cursor_captured: bool,
+    cursor_needs_recentering: bool,
 }
 
-impl ApplicationHandler for App<'_> {
-    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        let size = LogicalSize::new(WINDOW_WIDTH, WINDOW_HEIGHT);
-
-        let monitor = event_loop.primary_monitor().unwrap();
-        let modes: Vec<VideoModeHandle> = monitor.video_modes().collect();
-
-        // TODO: Choose a sensible video mode for exclusive fullscreen
-        let video_mode = modes[0].clone();
-
-        let window = Arc::new(
-            event_loop
-                .create_window(
-                    Window::default_attributes()
-                        // .with_fullscreen(Some(Fullscreen::Exclusive(video_mode)))
-                        // .with_fullscreen(Some(Fullscreen::Borderless(None)))
-                        .with_inner_size(size)
-                        .with_title("game"),
-                )
-                .unwrap(),
+/// Hides the cursor and stops it from leaving the window. Returns true if the platform can't lock
+/// the cursor in place, in which case it's confined instead and must be recentered manually.
+fn capture_cursor(window: &Window, capture: bool) -> Result<bool, ExternalError> {
+    let needs_recentering = if capture {
+        match window.set_cursor_grab(CursorGrabMode::Locked) {
+            Ok(()) => false,
+            Err(_) => {
+                window.set_cursor_grab(CursorGrabMode::Confined)?;
+                true
+            }
+        }
+    } else {
+        window.set_cursor_grab(CursorGrabMode::None)?;
+        false
+    };
+    window.set_cursor_visible(!capture);
+    Ok(needs_recentering)
+}
+
+/// Picks the mode matching the monitor's current resolution with the highest refresh rate,
+/// falling back to the first mode the monitor reports.
+fn choose_exclusive_video_mode(monitor: &MonitorHandle) -> Option<VideoModeHandle> {
+    let desktop_size = monitor.size();
+    let mode = monitor
+        .video_modes()
+        .filter(|mode| mode.size() == desktop_size)
+        .max_by_key(|mode| mode.refresh_rate_millihertz())
+        .or_else(|| monitor.video_modes().next());
+
+    if let Some(mode) = &mode {
+        log::info!(
+            "Exclusive fullscreen video mode: {}x{} @ {:.2}Hz",
+            mode.size().width,
+            mode.size().height,
+            mode.refresh_rate_millihertz() as f32 / 1000.0
         );
+    }
+    mode
+}
+
+/// The browser window's size, which the canvas fills instead of using the configured size.
+#[cfg(target_arch = "wasm32")]
+fn browser_window_size() -> Option<LogicalSize<u32>> {
+    let window = web_sys::window()?;
+    let width = window.inner_width().ok()?.as_f64()?;
+    let height = window.inner_height().ok()?.as_f64()?;
+    Some(LogicalSize::new(width as u32, height as u32))
+}
+
+fn is_quit_shortcut(code: KeyCode, modifiers: ModifiersState) -> bool {
+    if cfg!(target_os = "macos") {
+        code == KeyCode::KeyQ && modifiers.super_key()
+    } else {
+        (code == KeyCode::KeyQ && modifiers.control_key())
+            || (code == KeyCode::F4 && modifiers.alt_key())
+    }
+}
+
+impl ApplicationHandler<GpuCreated> for App {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        let size = LogicalSize::new(self.config.window_width, self.config.window_height);
+        let fullscreen = self
+            .config
+            .fullscreen
+            .then_some(Fullscreen::Borderless(None));
+
+        let attributes = Window::default_attributes()
+            .with_inner_size(size)
+            .with_fullscreen(fullscreen)
+            .with_title("game");
+
+        #[cfg(target_arch = "wasm32")]
+        let attributes = {
+            use winit::platform::web::WindowAttributesExtWebSys;
+            let size = browser_window_size().unwrap_or(size);
+            attributes.with_inner_size(size).with_append(true)
+        };
 
-        self.gpu = Some(Gpu::new(&window));
+        let window = Arc::new(event_loop.create_window(attributes).unwrap());
         self.window = Some(window.clone());
-        self.game = Some(Game::new());
+
+        let present_mode = self.config.wgpu_present_mode();
+
+        #[cfg(not(target_arch = "wasm32"))]
+        self.user_event(
+            event_loop,
+            pollster::block_on(Gpu::new(&window, present_mode)),
+        );
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            let proxy = self.proxy.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                let result = Gpu::new(&window, present_mode).await;
+                let _ = proxy.send_event(result);
+            });
+        }
+    }
+
+    fn user_event(&mut self, event_loop: &ActiveEventLoop, result: GpuCreated) {
+        match result {
+            Ok(gpu) => {
+                self.gpu = Some(gpu);
+                self.game = Some(Game::new());
+            }
+            Err(e) => {
+                log::error!("Failed to initialize graphics: {}", e);
+                event_loop.exit();
+            }
+        }
     }
 
     fn about_to_wait(&mut self, _event_loop: &ActiveEventLoop) {
@@ -63,8 +154,10 @@ impl ApplicationHandler for App<'_> {
     }
 
     fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
-        let gpu = self.gpu.as_mut().unwrap();
-        let game = self.game.as_mut().unwrap();
+        // Both are None until the Gpu has been created, or if that failed and the loop is exiting.
+        let (Some(gpu), Some(game)) = (self.gpu.as_mut(), self.game.as_mut()) else {
+            return;
+        };
         match event {
             WindowEvent::CursorMoved {
                 device_id: _,
@@ -74,6 +167,17 @@ impl ApplicationHandler for App<'_> {
                     let size = self.window.as_ref().unwrap().inner_size();
                     Vec2::new(size.width as f32, size.height as f32)
                 };
+
+                // While captured, the mouse is reported via MouseDelta in device_event instead.
+                if self.cursor_captured {
+                    if self.cursor_needs_recentering {
+                        let center =
+                            PhysicalPosition::new(size.x as f64 / 2.0, size.y as f64 / 2.0);
+                        let _ = self.window.as_ref().unwrap().set_cursor_position(center);
+                    }
+                    return;
+                }
+
                 self.mouse_pos = Vec2::new(position.x as f32, position.y as f32);
                 let normalized_coords = gpu.window_to_normalized(&self.mouse_pos);
                 game.push_event(Event::MousePos(normalized_coords));
@@ -83,36 +187,165 @@ impl ApplicationHandler for App<'_> {
                 state,
                 button,
             } => {
-                if button == MouseButton::Left {
-                    match state {
-                        ElementState::Pressed => {
-                            let normalized_coords = gpu.window_to_normalized(&self.mouse_pos);
-                            game.push_event(Event::LeftClickPressed(normalized_coords));
+                let normalized_coords = gpu.window_to_normalized(&self.mouse_pos);
+                let event = match (button, state) {
+                    (MouseButton::Left, ElementState::Pressed) => {
+                        Some(Event::LeftClickPressed(normalized_coords))
+                    }
+                    (MouseButton::Left, ElementState::Released) => {
+                        Some(Event::LeftClickReleased(normalized_coords))
+                    }
+                    (MouseButton::Middle, ElementState::Pressed) => {
+                        Some(Event::MiddleClickPressed(normalized_coords))
+                    }
+                    (MouseButton::Middle, ElementState::Released) => {
+                        Some(Event::MiddleClickReleased(normalized_coords))
+                    }
+                    _ => None,
+                };
+                if let Some(event) = event {
+                    game.push_event(event);
+                }
+            }
+            WindowEvent::MouseWheel {
+                device_id: _,
+                delta,
+                phase: _,
+            } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => Vec2::new(x, y),
+                    MouseScrollDelta::PixelDelta(pos) => {
+                        Vec2::new(pos.x as f32, pos.y as f32) / PIXELS_PER_SCROLL_LINE
+                    }
+                };
+                game.push_event(Event::Scroll(lines));
+            }
+            WindowEvent::KeyboardInput {
+                device_id: _,
+                event,
+                is_synthetic: _,
+            } => {
+                // Ignore OS key repeats so that hotkeys only fire once per press.
+                if let (PhysicalKey::Code(code), false) = (event.physical_key, event.repeat) {
+                    if event.state == ElementState::Pressed
+                        && is_quit_shortcut(code, self.modifiers)
+                    {
+                        event_loop.exit();
+                        return;
+                    }
+
+                    if code == CAPTURE_CURSOR_KEY && event.state == ElementState::Pressed {
+                        let capture = !self.cursor_captured;
+                        match capture_cursor(self.window.as_ref().unwrap(), capture) {
+                            Ok(needs_recentering) => {
+                                self.cursor_captured = capture;
+                                self.cursor_needs_recentering = needs_recentering;
+                            }
+                            Err(e) => log::warn!("Failed to capture the cursor: {}", e),
                         }
-                        ElementState::Released => {
-                            let normalized_coords = gpu.window_to_normalized(&self.mouse_pos);
-                            game.push_event(Event::LeftClickReleased(normalized_coords));
+                    }
+
+                    if code == FULLSCREEN_KEY && event.state == ElementState::Pressed {
+                        let window = self.window.as_ref().unwrap();
+                        if window.fullscreen().is_some() {
+                            window.set_fullscreen(None);
+                        } else if self.modifiers.shift_key() {
+                            let mode = window
+                                .current_monitor()
+                                .and_then(|monitor| choose_exclusive_video_mode(&monitor));
+                            window.set_fullscreen(mode.map(Fullscreen::Exclusive));
+                        } else {
+                            window.set_fullscreen(Some(Fullscreen::Borderless(None)));
                         }
+                        // The resulting WindowEvent::Resized resizes the gpu.
+                        window.request_redraw();
+                    }
+
+                    match event.state {
+                        ElementState::Pressed => game.push_event(Event::KeyPressed(code)),
+                        ElementState::Released => game.push_event(Event::KeyReleased(code)),
+                    }
+                }
+
+                // Text is forwarded even for repeats, so that holding a key types it repeatedly.
+                if let (ElementState::Pressed, Some(text)) = (event.state, &event.text) {
+                    // Control characters such as backspace are handled as key presses instead.
+                    if !text.chars().any(char::is_control) {
+                        game.push_event(Event::Text(text.to_string()));
                     }
                 }
             }
-            WindowEvent::CloseRequested => event_loop.exit(), // TODO: call this when doing cmd+Q etc
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers.state();
+                game.push_event(Event::ModifiersChanged(modifiers.state()));
+            }
+            WindowEvent::Resized(size) => {
+                gpu.resize(size.width, size.height);
+                self.window.as_ref().unwrap().request_redraw();
+            }
+            WindowEvent::CloseRequested => event_loop.exit(),
             WindowEvent::RedrawRequested => {
-                game.update_and_render(gpu);
+                if let Err(e) = game.update_and_render(gpu) {
+                    log::error!("Failed to render: {}", e);
+                    event_loop.exit();
+                }
             }
             _ => (),
         }
     }
+
+    fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
+        // Drop the game here rather than relying on run_app returning, so the grid is always saved.
+        self.game = None;
+
+        // Remember the window size for next time. The size isn't meaningful while fullscreen.
+        if let Some(window) = &self.window {
+            self.config.fullscreen = window.fullscreen().is_some();
+            if !self.config.fullscreen {
+                let size: LogicalSize<u32> = window.inner_size().to_logical(window.scale_factor());
+                self.config.window_width = size.width;
+                self.config.window_height = size.height;
+            }
+        }
+        self.config.save();
+    }
+
+    fn device_event(&mut self, _event_loop: &ActiveEventLoop, _id: DeviceId, event: DeviceEvent) {
+        if let (DeviceEvent::MouseMotion { delta }, true) = (event, self.cursor_captured) {
+            if let Some(game) = self.game.as_mut() {
+                game.push_event(Event::MouseDelta(Vec2::new(delta.0 as f32, delta.1 as f32)));
+            }
+        }
+    }
 }
 
 fn main() {
-    let event_loop = EventLoop::new().unwrap();
+    // The game's info messages and everyone's warnings are shown unless RUST_LOG overrides it.
+    #[cfg(not(target_arch = "wasm32"))]
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn,game=info"))
+        .init();
+
+    let event_loop = EventLoop::<GpuCreated>::with_user_event().build().unwrap();
     event_loop.set_control_flow(ControlFlow::Poll);
     let mut app = App {
+        config: Config::load(),
+        proxy: event_loop.create_proxy(),
         game: None,
         window: None,
         gpu: None,
         mouse_pos: Vec2::ZERO,
+        modifiers: ModifiersState::empty(),
+        cursor_captured: false,
+        cursor_needs_recentering: false,
     };
+
+    #[cfg(not(target_arch = "wasm32"))]
     let _ = event_loop.run_app(&mut app);
+
+    // The browser owns the event loop, so this returns immediately and the app lives on in it.
+    #[cfg(target_arch = "wasm32")]
+    {
+        use winit::platform::web::EventLoopExtWebSys;
+        event_loop.spawn_app(app);
+    }
 }
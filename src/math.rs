@@ -1,17 +1,60 @@
 use crate::prelude::*;
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        debug_assert!(min.cmple(max).all());
+        Self { min, max }
+    }
+
+    pub fn from_points(points: &[Vec3]) -> Option<Self> {
+        let first = *points.first()?;
+        Some(points.iter().fold(Self::new(first, first), |aabb, p| Self {
+            min: aabb.min.min(*p),
+            max: aabb.max.max(*p),
+        }))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+}
+
 pub fn transform_2d(pos: &Vec2, mat: &Mat4) -> Vec2 {
     let pos4 = Vec4::new(pos.x, pos.y, 0.0, 1.0);
     (*mat * pos4).xy()
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RayHit {
+    pub point: Vec3,
+    pub normal: Vec3, // The triangle's geometric normal, following its counterclockwise winding
+    pub barycentric: Vec3, // The weights of the triangle's three vertices at the hit point
+}
+
 fn ray_triangle_intersection(
     origin: Vec3,
     direction: Vec3,
     t_a: Vec3,
     t_b: Vec3,
     t_c: Vec3,
-) -> Option<Vec3> {
+) -> Option<RayHit> {
     let e1 = t_b - t_a;
     let e2 = t_c - t_a;
 
@@ -39,14 +82,83 @@ fn ray_triangle_intersection(
 
     if t > f32::EPSILON {
         // ray intersection
-        let intersection_point = origin + direction * t;
-        return Some(intersection_point);
+        return Some(RayHit {
+            point: origin + direction * t,
+            normal: e1.cross(e2).normalize(),
+            barycentric: Vec3::new(1.0 - u - v, u, v),
+        });
     } else {
         // This means that there is a line intersection but not a ray intersection.
         return None;
     }
 }
 
+/// Tests the ray against every triangle in a triangle list, returning the nearest hit and the
+/// index of the triangle it hit.
+pub fn ray_mesh_intersection(
+    origin: Vec3,
+    direction: Vec3,
+    positions: &[Vec3],
+) -> Option<(RayHit, usize)> {
+    debug_assert_eq!(positions.len() % 3, 0);
+
+    let mut nearest: Option<(RayHit, usize)> = None;
+    for (i, triangle) in positions.chunks_exact(3).enumerate() {
+        let hit = match ray_triangle_intersection(
+            origin,
+            direction,
+            triangle[0],
+            triangle[1],
+            triangle[2],
+        ) {
+            Some(hit) => hit,
+            None => continue,
+        };
+
+        let is_nearer = nearest.map_or(true, |(nearest_hit, _)| {
+            hit.point.distance_squared(origin) < nearest_hit.point.distance_squared(origin)
+        });
+        if is_nearer {
+            nearest = Some((hit, i));
+        }
+    }
+    nearest
+}
+
+/// Returns where the ray enters the box and the normal of the face it enters through, using the
+/// slab method. A ray starting inside the box doesn't hit it.
+pub fn ray_aabb_intersection(origin: Vec3, direction: Vec3, aabb: &Aabb) -> Option<(Vec3, Vec3)> {
+    let mut t_enter = f32::NEG_INFINITY;
+    let mut t_exit = f32::INFINITY;
+    let mut normal = Vec3::ZERO;
+
+    for axis in 0..3 {
+        if direction[axis] == 0.0 {
+            if origin[axis] < aabb.min[axis] || origin[axis] > aabb.max[axis] {
+                return None;
+            }
+            continue;
+        }
+
+        let t_min = (aabb.min[axis] - origin[axis]) / direction[axis];
+        let t_max = (aabb.max[axis] - origin[axis]) / direction[axis];
+        let (t_near, t_far) = (t_min.min(t_max), t_min.max(t_max));
+
+        if t_near > t_enter {
+            t_enter = t_near;
+            // The entry face points back along the ray on the axis of the last slab entered.
+            normal = Vec3::ZERO;
+            normal[axis] = -direction[axis].signum();
+        }
+        t_exit = t_exit.min(t_far);
+    }
+
+    if t_enter > t_exit || t_enter < 0.0 {
+        return None;
+    }
+    Some((origin + direction * t_enter, normal))
+}
+
 pub fn cube_triangles() -> Vec<Vec3> {
     let left_bottom_front = Vec3::new(0.0, 0.0, 0.0);
     let right_bottom_front = Vec3::new(1.0, 0.0, 0.0);
@@ -102,17 +214,116 @@ pub fn cube_triangles() -> Vec<Vec3> {
     ]
 }
 
+/// A sphere of radius 1 around the origin as a triangle list, with rings of latitude from pole
+/// to pole and segments of longitude around the y axis.
+pub fn sphere_triangles(rings: usize, segments: usize) -> Vec<Vec3> {
+    let point = |ring: usize, segment: usize| {
+        let latitude = std::f32::consts::PI * ring as f32 / rings as f32;
+        let longitude = std::f32::consts::TAU * segment as f32 / segments as f32;
+        Vec3::new(
+            latitude.sin() * longitude.cos(),
+            latitude.cos(),
+            latitude.sin() * longitude.sin(),
+        )
+    };
+
+    let mut triangles = Vec::with_capacity(rings * segments * 6);
+    for ring in 0..rings {
+        for segment in 0..segments {
+            let top_left = point(ring, segment);
+            let top_right = point(ring, segment + 1);
+            let bottom_left = point(ring + 1, segment);
+            let bottom_right = point(ring + 1, segment + 1);
+            triangles.extend_from_slice(&[top_left, bottom_left, top_right]);
+            triangles.extend_from_slice(&[top_right, bottom_left, bottom_right]);
+        }
+    }
+    triangles
+}
+
+/// The 12 edges of the unit cube from cube_triangles, as pairs of positions for a line mesh.
+pub fn cube_edges() -> Vec<Vec3> {
+    let mut edges = Vec::with_capacity(24);
+    for a in 0..2 {
+        for b in 0..2 {
+            let (a, b) = (a as f32, b as f32);
+            // One edge along each axis through this corner of the other two.
+            edges.extend_from_slice(&[Vec3::new(0.0, a, b), Vec3::new(1.0, a, b)]);
+            edges.extend_from_slice(&[Vec3::new(a, 0.0, b), Vec3::new(a, 1.0, b)]);
+            edges.extend_from_slice(&[Vec3::new(a, b, 0.0), Vec3::new(a, b, 1.0)]);
+        }
+    }
+    edges
+}
+
+/// Expands each segment into a quad in normalized coordinates, after projecting its ends with
+/// the matrix, so the lines have the same thickness however far away they are. The quad is
+/// bordered on both sides by a fringe that fades out for antialiasing. Returns 18 positions per
+/// segment, each with the opacity of its vertex: 1 at the core and 0 at the fringe's edge.
+pub fn thick_line_triangles(
+    segments: &[(Vec3, Vec3)],
+    matrix: &Mat4,
+    half_width: f32,
+    fringe: f32,
+) -> Vec<(Vec3, f32)> {
+    let mut triangles = Vec::with_capacity(segments.len() * 18);
+    for (a, b) in segments {
+        let a = matrix.project_point3(*a);
+        let b = matrix.project_point3(*b);
+        let along = (b - a).truncate().normalize_or_zero();
+        let across = Vec3::new(-along.y, along.x, 0.0);
+
+        // The cross section from one fringe edge to the other.
+        let offsets = [
+            (-half_width - fringe, 0.0),
+            (-half_width, 1.0),
+            (half_width, 1.0),
+            (half_width + fringe, 0.0),
+        ];
+        for pair in offsets.windows(2) {
+            let ((near, near_alpha), (far, far_alpha)) = (pair[0], pair[1]);
+            let a_near = (a + across * near, near_alpha);
+            let a_far = (a + across * far, far_alpha);
+            let b_near = (b + across * near, near_alpha);
+            let b_far = (b + across * far, far_alpha);
+            triangles.extend_from_slice(&[a_near, b_near, a_far, a_far, b_near, b_far]);
+        }
+    }
+    triangles
+}
+
+/// Evaluates a Catmull-Rom spline through the points, where t runs from 0 at the first point to
+/// points.len() - 1 at the last and is clamped to that range. The end points are duplicated to
+/// give the end segments their tangents. Returns zero if there are no points.
+pub fn catmull_rom(points: &[Vec3], t: f32) -> Vec3 {
+    let Some(last) = points.len().checked_sub(1) else {
+        return Vec3::ZERO;
+    };
+    let t = t.clamp(0.0, last as f32);
+    let segment = (t as usize).min(last.saturating_sub(1));
+    let f = t - segment as f32;
+
+    let point = |i: isize| points[i.clamp(0, last as isize) as usize];
+    let i = segment as isize;
+    let (p0, p1, p2, p3) = (point(i - 1), point(i), point(i + 1), point(i + 2));
+
+    0.5 * (2.0 * p1
+        + (p2 - p0) * f
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * f * f
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * f * f * f)
+}
+
 // fn intersect_grid_1d(cube_size: i32, ray_start: f32, ray_end: f32) -> Vec<i32> {
 // }
 
 #[derive(PartialEq)]
-enum CheckFace {
+pub enum CheckFace {
     Front,
     Back,
     Both,
 }
 
-fn plane_ray_intersection(
+pub fn plane_ray_intersection(
     plane_normal: Vec3,
     plane_point: Vec3,
     ray_origin: Vec3,
@@ -138,6 +349,136 @@ fn plane_ray_intersection(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_aabb() {
+        let a = Aabb::from_points(&[Vec3::new(1.0, 0.0, 2.0), Vec3::new(-1.0, 3.0, 0.0)]).unwrap();
+        assert_eq!(a.min, Vec3::new(-1.0, 0.0, 0.0));
+        assert_eq!(a.max, Vec3::new(1.0, 3.0, 2.0));
+        assert_eq!(a.center(), Vec3::new(0.0, 1.5, 1.0));
+
+        let b = Aabb::new(Vec3::ZERO, Vec3::splat(5.0));
+        assert_eq!(
+            a.union(&b),
+            Aabb::new(Vec3::new(-1.0, 0.0, 0.0), Vec3::splat(5.0))
+        );
+
+        assert!(Aabb::from_points(&[]).is_none());
+    }
+
+    #[test]
+    fn test_ray_mesh_intersection() {
+        let near_triangle = [
+            Vec3::new(-1.0, -1.0, 1.0),
+            Vec3::new(1.0, -1.0, 1.0),
+            Vec3::new(0.0, 1.0, 1.0),
+        ];
+        let far_triangle = near_triangle.map(|v| v + Vec3::Z);
+        let origin = Vec3::ZERO;
+
+        // A single triangle in front of the ray.
+        let (hit, index) = ray_mesh_intersection(origin, Vec3::Z, &near_triangle).unwrap();
+        assert_eq!(hit.point, Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(index, 0);
+        assert_eq!(hit.normal, Vec3::Z);
+        assert_eq!(hit.barycentric, Vec3::new(0.25, 0.25, 0.5));
+
+        // Pointing away from it, or off to the side.
+        assert!(ray_mesh_intersection(origin, -Vec3::Z, &near_triangle).is_none());
+        let beside = Vec3::new(5.0, 0.0, 0.0);
+        assert!(ray_mesh_intersection(beside, Vec3::Z, &near_triangle).is_none());
+
+        // The nearest of two overlapping triangles wins regardless of their order.
+        let mut positions = far_triangle.to_vec();
+        positions.extend_from_slice(&near_triangle);
+        let (hit, index) = ray_mesh_intersection(origin, Vec3::Z, &positions).unwrap();
+        assert_eq!(hit.point, Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(index, 1);
+    }
+
+    #[test]
+    fn test_ray_aabb_intersection() {
+        let aabb = Aabb::new(Vec3::ZERO, Vec3::ONE);
+
+        let hit = ray_aabb_intersection(Vec3::new(0.5, 0.5, -1.0), Vec3::Z, &aabb);
+        assert_eq!(hit, Some((Vec3::new(0.5, 0.5, 0.0), -Vec3::Z)));
+
+        // Entering through the top face at an angle.
+        let direction = Vec3::new(1.0, -1.0, 0.0).normalize();
+        let (point, normal) =
+            ray_aabb_intersection(Vec3::new(0.0, 1.5, 0.5), direction, &aabb).unwrap();
+        assert!(point.abs_diff_eq(Vec3::new(0.5, 1.0, 0.5), 0.0001));
+        assert_eq!(normal, Vec3::Y);
+
+        assert!(ray_aabb_intersection(Vec3::new(2.0, 0.5, -1.0), Vec3::Z, &aabb).is_none());
+        assert!(ray_aabb_intersection(Vec3::new(0.5, 0.5, -1.0), -Vec3::Z, &aabb).is_none());
+    }
+
+    #[test]
+    fn test_thick_line_triangles() {
+        let segments = [(Vec3::ZERO, Vec3::X)];
+        let triangles = thick_line_triangles(&segments, &Mat4::IDENTITY, 0.1, 0.05);
+        assert_eq!(triangles.len(), 18);
+
+        // The line runs along x, so the cross section spreads along y.
+        for (pos, alpha) in triangles {
+            assert!(pos.x == 0.0 || pos.x == 1.0);
+            if alpha == 1.0 {
+                assert!((pos.y.abs() - 0.1).abs() < 0.0001);
+            } else {
+                assert_eq!(alpha, 0.0);
+                assert!((pos.y.abs() - 0.15).abs() < 0.0001);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sphere_triangles() {
+        let triangles = sphere_triangles(4, 8);
+        assert_eq!(triangles.len(), 4 * 8 * 6);
+        for pos in triangles {
+            assert!((pos.length() - 1.0).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_cube_edges() {
+        let edges = cube_edges();
+        assert_eq!(edges.len(), 24);
+        for edge in edges.chunks(2) {
+            assert_eq!(edge[0].distance(edge[1]), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_catmull_rom() {
+        let points = [
+            Vec3::ZERO,
+            Vec3::new(1.0, 2.0, 0.0),
+            Vec3::new(3.0, -1.0, 1.0),
+            Vec3::new(4.0, 0.0, 2.0),
+        ];
+        for (i, point) in points.iter().enumerate() {
+            assert!(catmull_rom(&points, i as f32).distance(*point) < 0.0001);
+        }
+
+        // Outside the spline, t is clamped to the end points.
+        assert_eq!(catmull_rom(&points, -1.0), points[0]);
+        assert!(catmull_rom(&points, 10.0).distance(points[3]) < 0.0001);
+
+        // Between evenly spaced points on a line, away from the ends, the spline is linear.
+        let line = [Vec3::ZERO, Vec3::X, Vec3::X * 2.0, Vec3::X * 3.0];
+        assert!(catmull_rom(&line, 1.5).distance(Vec3::X * 1.5) < 0.0001);
+
+        assert_eq!(catmull_rom(&[Vec3::ONE], 0.5), Vec3::ONE);
+        assert_eq!(catmull_rom(&[], 0.5), Vec3::ZERO);
+    }
+
+    #[test]
+    fn test_cube_bounds() {
+        let bounds = Aabb::from_points(&cube_triangles()).unwrap();
+        assert_eq!(bounds, Aabb::new(Vec3::ZERO, Vec3::ONE));
+    }
+
     #[test]
     fn test_plane_ray_intersection() {
         let r_origin = Vec3::new(1.5, 0.5, -1.0);
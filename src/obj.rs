@@ -0,0 +1,156 @@
+use crate::prelude::*;
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+/// Positions, normals and UVs with one entry per vertex of a triangle list. Normals and UVs are
+/// only Some if every face vertex in the file has them.
+pub type ObjTriangles = (Vec<Vec3>, Option<Vec<Vec3>>, Option<Vec<Vec2>>);
+
+/// Loads the faces of an OBJ file as a triangle list for Mesh::new. Faces with more than three
+/// vertices are triangulated as fans, and materials and groups are ignored.
+pub fn load_obj(path: &str) -> Result<ObjTriangles, Error> {
+    let contents = fs::read_to_string(path)?;
+    parse_obj(&contents)
+}
+
+pub fn parse_obj(contents: &str) -> Result<ObjTriangles, Error> {
+    let invalid =
+        |line: &str| Error::new(ErrorKind::InvalidData, format!("Bad OBJ line: {}", line));
+
+    let mut file_positions = vec![];
+    let mut file_normals = vec![];
+    let mut file_uvs = vec![];
+
+    let mut positions = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut has_normals = true;
+    let mut has_uvs = true;
+
+    for line in contents.lines() {
+        let mut words = line.split_whitespace();
+        let Some(keyword) = words.next() else {
+            continue;
+        };
+        let mut floats = || -> Result<Vec<f32>, Error> {
+            words
+                .by_ref()
+                .map(|word| word.parse().map_err(|_| invalid(line)))
+                .collect()
+        };
+
+        match keyword {
+            "v" => match floats()?[..] {
+                [x, y, z, ..] => file_positions.push(Vec3::new(x, y, z)),
+                _ => return Err(invalid(line)),
+            },
+            "vn" => match floats()?[..] {
+                [x, y, z, ..] => file_normals.push(Vec3::new(x, y, z)),
+                _ => return Err(invalid(line)),
+            },
+            // OBJ's v runs up the image, but texture rows run down it.
+            "vt" => match floats()?[..] {
+                [u, v, ..] => file_uvs.push(Vec2::new(u, 1.0 - v)),
+                [u] => file_uvs.push(Vec2::new(u, 1.0)),
+                _ => return Err(invalid(line)),
+            },
+            "f" => {
+                let mut face = vec![];
+                for vertex in words {
+                    // Each vertex is position/uv/normal, where uv and normal are optional.
+                    let mut indices = vertex.split('/');
+                    let mut lookup = |count: usize| -> Result<Option<usize>, Error> {
+                        match indices.next() {
+                            None | Some("") => Ok(None),
+                            Some(index) => {
+                                let index: i64 = index.parse().map_err(|_| invalid(line))?;
+                                obj_index(index, count)
+                                    .map(Some)
+                                    .ok_or_else(|| invalid(line))
+                            }
+                        }
+                    };
+                    let position = lookup(file_positions.len())?.ok_or_else(|| invalid(line))?;
+                    let uv = lookup(file_uvs.len())?;
+                    let normal = lookup(file_normals.len())?;
+                    face.push((position, uv, normal));
+                }
+                if face.len() < 3 {
+                    return Err(invalid(line));
+                }
+
+                for i in 1..face.len() - 1 {
+                    for (position, uv, normal) in [face[0], face[i], face[i + 1]] {
+                        positions.push(file_positions[position]);
+                        match uv {
+                            Some(uv) => uvs.push(file_uvs[uv]),
+                            None => has_uvs = false,
+                        }
+                        match normal {
+                            Some(normal) => normals.push(file_normals[normal]),
+                            None => has_normals = false,
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let normals = has_normals.then_some(normals);
+    let uvs = has_uvs.then_some(uvs);
+    Ok((positions, normals, uvs))
+}
+
+/// Converts a 1-based OBJ index, or a negative one that counts back from the latest element, to
+/// a 0-based index. Returns None if it's out of range.
+fn obj_index(index: i64, count: usize) -> Option<usize> {
+    let index = if index < 0 {
+        count as i64 + index
+    } else {
+        index - 1
+    };
+    (0..count as i64).contains(&index).then_some(index as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_obj() {
+        let obj = "
+            # A unit quad and a triangle that refers back to it with negative indices
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0
+            vt 0 0
+            vt 1 0
+            vt 1 1
+            vt 0 1
+            vn 0 0 1
+            usemtl ignored
+            f 1/1/1 2/2/1 3/3/1 4/4/1
+            f -4/-4/-1 -3/-3/-1 -2/-2/-1
+        ";
+        let (positions, normals, uvs) = parse_obj(obj).unwrap();
+
+        // The quad is a fan of two triangles.
+        assert_eq!(positions.len(), 9);
+        assert_eq!(positions[0], Vec3::ZERO);
+        assert_eq!(positions[5], Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(normals.unwrap()[8], Vec3::Z);
+        assert_eq!(uvs.unwrap()[0], Vec2::new(0.0, 1.0));
+    }
+
+    #[test]
+    fn test_parse_obj_without_normals() {
+        let (positions, normals, uvs) = parse_obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3").unwrap();
+        assert_eq!(positions.len(), 3);
+        assert!(normals.is_none());
+        assert!(uvs.is_none());
+
+        assert!(parse_obj("v 0 0 0\nf 1 2 3").is_err());
+    }
+}
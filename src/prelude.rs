@@ -1,3 +1,4 @@
+pub use crate::camera::Camera;
 pub use crate::debugger::Debugger;
 pub use crate::gpu::{Gpu, Mesh};
 pub use glam::{
@@ -7,10 +8,42 @@ pub use glam::{
 pub use rand::prelude::*;
 pub use std::collections::{HashMap, HashSet, VecDeque};
 pub use std::f32::consts::SQRT_2;
+// std's clock panics in the browser.
+#[cfg(not(target_arch = "wasm32"))]
 pub use std::time::{Duration, Instant};
+#[cfg(target_arch = "wasm32")]
+pub use web_time::{Duration, Instant};
+pub use winit::keyboard::{KeyCode, ModifiersState};
 
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Event {
     LeftClickPressed(Vec2),
     LeftClickReleased(Vec2),
+    DoubleClick(Vec2),
+    DragStart(Vec2),
+    Drag {
+        start: Vec2,
+        current: Vec2,
+    },
+    DragEnd {
+        start: Vec2,
+        end: Vec2,
+    },
+    MiddleClickPressed(Vec2),
+    MiddleClickReleased(Vec2),
     MousePos(Vec2),
+    /// Raw mouse movement in pixels, only sent while the cursor is captured.
+    MouseDelta(Vec2),
+    /// Scroll wheel movement in lines.
+    Scroll(Vec2),
+    KeyPressed(KeyCode),
+    KeyReleased(KeyCode),
+    ModifiersChanged(ModifiersState),
+    Text(String),
+    /// Camera orbit velocity from an analog source, each axis in the range -1 to 1.
+    Orbit(Vec2),
+    /// Camera zoom velocity from an analog source, in the range -1 to 1.
+    Zoom(f32),
 }
@@ -34,11 +34,14 @@ struct VertToFrag {
     @builtin(position) pos: vec4<f32>,
     @location(0) color: vec4<f32>,
     @location(1) uv: vec2<f32>,
+    @location(2) depth: f32,
 }
 
 struct Uniform {
     matrix: mat4x4<f32>,
     color: vec4<f32>,
+    exposure: f32,
+    fog: vec4<f32>, // sRGB color and density
 }
 @group(0) @binding(0)
 var<uniform> uniform: Uniform;
@@ -55,6 +58,7 @@ fn vs_main(@builtin(vertex_index) vert_index: u32, vert: VertInput) -> VertToFra
     out.color.z *= 1.0 - out.pos.z;
 
     out.uv = vert.uv;
+    out.depth = out.pos.z; // Before the perspective divide, so linear in view space
     return out;
 }
 
@@ -72,5 +76,12 @@ fn fs_main(in: VertToFrag) -> @location(0) vec4<f32> {
     let vert_color = srgb_to_linear(in.color);
 
     // TODO: Should any of these get converted to linear as well?
-    return tex_color * vert_color * uniform.color;
+    let color = tex_color * vert_color * uniform.color;
+
+    let fog_color = srgb_to_linear(vec4<f32>(uniform.fog.rgb, 1.0)).rgb;
+    let fog_amount = 1.0 - exp(-uniform.fog.a * max(in.depth, 0.0));
+    let fogged = mix(color.rgb, fog_color, fog_amount);
+
+    // The surface encodes to sRGB after this, so exposure is applied in linear space.
+    return vec4<f32>(fogged * uniform.exposure, color.a);
 }
\ No newline at end of file
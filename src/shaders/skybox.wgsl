@@ -0,0 +1,40 @@
+struct Uniform {
+    matrix: mat4x4<f32>, // From clip space back to the scene, the inverse of a mesh's matrix
+    color: vec4<f32>,
+    exposure: f32,
+    fog: vec4<f32>, // Unused, the sky isn't fogged
+}
+@group(0) @binding(0)
+var<uniform> uniform: Uniform;
+
+struct VertToFrag {
+    @builtin(position) pos: vec4<f32>,
+    @location(0) clip_pos: vec2<f32>,
+}
+
+// A single triangle that covers the whole target, with no vertex buffers.
+@vertex
+fn vs_main(@builtin(vertex_index) vert_index: u32) -> VertToFrag {
+    let corner = vec2<f32>(f32((vert_index << 1u) & 2u), f32(vert_index & 2u));
+    var out: VertToFrag;
+    out.clip_pos = corner * 2.0 - 1.0;
+    out.pos = vec4<f32>(out.clip_pos, 0.0, 1.0);
+    return out;
+}
+
+@group(1) @binding(0)
+var cubemap_view: texture_cube<f32>;
+@group(1) @binding(1)
+var cubemap_sampler: sampler;
+
+@fragment
+fn fs_main(in: VertToFrag) -> @location(0) vec4<f32> {
+    // The view direction through this pixel is from its point on the near plane to its point on
+    // the far plane, which leaves out the camera's position.
+    let near = uniform.matrix * vec4<f32>(in.clip_pos, 0.0, 1.0);
+    let far = uniform.matrix * vec4<f32>(in.clip_pos, 1.0, 1.0);
+    let direction = far.xyz / far.w - near.xyz / near.w;
+
+    let color = textureSample(cubemap_view, cubemap_sampler, direction) * uniform.color;
+    return vec4<f32>(color.rgb * uniform.exposure, 1.0);
+}